@@ -0,0 +1,108 @@
+//! Parsers for address headers (`From`, `To`, `Cc`, ...).
+
+use super::{decode, unfold, unquote};
+
+/// A mailbox from an address header: an email address with an optional display name.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Address {
+    pub display_name: Option<String>,
+    pub email: String,
+}
+
+/// Parse the value of an address header into its mailboxes
+/// ([RFC 5322 section 3.4](https://tools.ietf.org/html/rfc5322#section-3.4)).
+///
+/// Mailboxes are separated by commas, or by line breaks which don't start a folded continuation
+/// line, as some generators list one address per line without commas. Display names are unquoted
+/// and have encoded-words decoded. A comment is used as the display name of an address which has
+/// none (e.g. `user@example.com (User)`), and is otherwise dropped. Groups are flattened into
+/// their members, discarding the group name. Anything which isn't an address is skipped.
+///
+/// ```
+/// use crumble::address::{Address, parse_address_list};
+/// let addresses = parse_address_list("\"Doe, John\" <john@example.com>, jane@example.com");
+/// assert_eq!(addresses, vec![
+///     Address { display_name: Some(String::from("Doe, John")), email: String::from("john@example.com") },
+///     Address { display_name: None, email: String::from("jane@example.com") },
+/// ]);
+/// ```
+pub fn parse_address_list(value: &str) -> Vec<Address> {
+    let value = unfold(value);
+
+    let mut addresses = Vec::new();
+    let mut mailbox = String::new();
+    let mut comment = String::new();
+    let mut chars = value.chars();
+    // Separators only count outside quoted strings, angle brackets and comments
+    let mut quoted = false;
+    let mut bracketed = false;
+    let mut comment_depth = 0;
+    while let Some(c) = chars.next() {
+        if comment_depth > 0 {
+            match c {
+                '(' => comment_depth += 1,
+                ')' => comment_depth -= 1,
+                '\\' => {
+                    comment.extend(chars.next());
+                    continue;
+                },
+                _ => (),
+            }
+            if comment_depth > 0 {
+                comment.push(c);
+            }
+            continue;
+        }
+        match c {
+            '\\' if quoted => {
+                mailbox.push(c);
+                mailbox.extend(chars.next());
+            },
+            '"' if !bracketed => {
+                quoted = !quoted;
+                mailbox.push(c);
+            },
+            _ if quoted => mailbox.push(c),
+            '<' => {
+                bracketed = true;
+                mailbox.push(c);
+            },
+            '>' => {
+                bracketed = false;
+                mailbox.push(c);
+            },
+            _ if bracketed => mailbox.push(c),
+            '(' => comment_depth = 1,
+            // The name of a group comes before its members
+            ':' => mailbox.clear(),
+            ',' | ';' | '\r' | '\n' => {
+                addresses.extend(parse_mailbox(&mailbox, &comment));
+                mailbox.clear();
+                comment.clear();
+            },
+            c => mailbox.push(c),
+        }
+    }
+    addresses.extend(parse_mailbox(&mailbox, &comment));
+    addresses
+}
+
+// Parse a single mailbox, either name-addr (Name <email>) or a bare addr-spec.
+fn parse_mailbox(mailbox: &str, comment: &str) -> Option<Address> {
+    let (display_name, email) = match (mailbox.find('<'), mailbox.rfind('>')) {
+        (Some(start), Some(end)) if start < end => (Some(&mailbox[..start]), &mailbox[start + 1..end]),
+        _ => (None, mailbox),
+    };
+    let email = email.trim();
+    if email.is_empty() {
+        return None;
+    }
+
+    let display_name = display_name.map(|name| unquote(name.trim())).filter(|name| !name.is_empty())
+        .or_else(|| Some(comment.trim().to_string()).filter(|name| !name.is_empty()))
+        .map(|name| decode::decode_encoded_words(&name).unwrap_or(name));
+    Some(Address {
+        display_name,
+        email: email.to_string(),
+    })
+}
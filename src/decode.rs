@@ -0,0 +1,226 @@
+//! Decoders for MIME transfer encodings.
+
+use super::Error;
+use regex::Regex;
+
+/// Decode a base64 body, ignoring the line breaks and other whitespace used to wrap it.
+///
+/// Returns `Error::ParseError` if the input has characters outside the base64 alphabet or a
+/// length no encoding could have, rather than decoding as much as possible, so corrupted bodies
+/// can be detected. Missing padding is tolerated.
+///
+/// ```
+/// use crumble::decode::decode_base64;
+/// assert_eq!(decode_base64(b"SGVsbG8s\r\nIHdvcmxk\r\nIQ==\r\n").unwrap(), b"Hello, world!");
+/// assert!(decode_base64(b"SGVsbG8*").is_err());
+/// ```
+pub fn decode_base64(input: &[u8]) -> Result<Vec<u8>, Error> {
+    let stripped: Vec<u8> = input.iter().filter(|b| !b.is_ascii_whitespace()).cloned().collect();
+    base64::decode_config(&stripped, base64::STANDARD).map_err(|_| Error::ParseError)
+}
+
+/// Decode a quoted-printable body ([RFC 2045 section 6.7](https://tools.ietf.org/html/rfc2045#section-6.7)).
+///
+/// Soft line breaks (`=` at the end of a line) are removed, joining the lines either side, and
+/// `=XX` escapes become bytes. Other line breaks are kept as they are. An `=` not followed by two
+/// hex digits is also kept, as the RFC recommends for robustness, so no input is currently
+/// rejected.
+///
+/// ```
+/// use crumble::decode::decode_quoted_printable;
+/// let decoded = decode_quoted_printable(b"check it out a=\nt: Strid=C5=BEie dni\n").unwrap();
+/// assert_eq!(decoded, "check it out at: Strid\u{17e}ie dni\n".as_bytes());
+/// ```
+pub fn decode_quoted_printable(input: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut decoded = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        if input[i] != b'=' {
+            decoded.push(input[i]);
+            i += 1;
+            continue;
+        }
+        match &input[i + 1..] {
+            [b'\r', b'\n', ..] => i += 3,
+            [b'\n', ..] => i += 2,
+            [] => i += 1,
+            rest => match rest.get(..2).map(hex_byte) {
+                Some(Ok(byte)) => {
+                    decoded.push(byte);
+                    i += 3;
+                },
+                _ => {
+                    decoded.push(b'=');
+                    i += 1;
+                },
+            },
+        }
+    }
+    Ok(decoded)
+}
+
+/// Check a body declared as `7bit` and return it unchanged, as it is not encoded.
+///
+/// Returns `Error::ParseError` if the body breaks the rules for 7bit data
+/// ([RFC 2045 section 2.7](https://tools.ietf.org/html/rfc2045#section-2.7)): a byte above 127 or
+/// a NUL byte, or a line longer than 998 bytes. High bytes usually mean the part was mislabelled
+/// and is really 8bit.
+///
+/// ```
+/// use crumble::decode::decode_7bit;
+/// assert_eq!(decode_7bit(b"Hello\n").unwrap(), b"Hello\n");
+/// assert!(decode_7bit("Gr\u{fc}\u{df}e\n".as_bytes()).is_err());
+/// ```
+pub fn decode_7bit(input: &[u8]) -> Result<Vec<u8>, Error> {
+    if !input.is_ascii() {
+        return Err(Error::ParseError);
+    }
+    decode_8bit(input)
+}
+
+/// Check a body declared as `8bit` and return it unchanged, as it is not encoded.
+///
+/// Returns `Error::ParseError` if the body breaks the rules for 8bit data
+/// ([RFC 2045 section 2.8](https://tools.ietf.org/html/rfc2045#section-2.8)): a NUL byte, or a line
+/// longer than 998 bytes. Bodies with either need the `binary` encoding, which has no rules.
+pub fn decode_8bit(input: &[u8]) -> Result<Vec<u8>, Error> {
+    let too_long = input.split(|&b| b == b'\n').any(|line| line.strip_suffix(b"\r").unwrap_or(line).len() > 998);
+    if too_long || input.contains(&0) {
+        return Err(Error::ParseError);
+    }
+    Ok(input.to_vec())
+}
+
+// Decode RFC 2047 encoded-words (=?charset?encoding?text?=) in a header value. Text outside
+// encoded-words is left untouched, and whitespace between adjacent encoded-words is dropped.
+pub(crate) fn decode_encoded_words(value: &str) -> Result<String, Error> {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"=\?(?P<charset>[^?\s]+)\?(?P<encoding>[BbQq])\?(?P<text>[^?\s]*)\?=").unwrap();
+    }
+
+    let mut decoded = String::new();
+    // Bytes of adjacent encoded-words in the same charset are joined before conversion: a
+    // multi-byte character may be split across several =XX escapes, or (against the RFC, but
+    // in the wild) across words, so bytes can only be interpreted once the run is complete.
+    let mut pending: Option<(&str, Vec<u8>)> = None;
+    let mut last = 0;
+    for word in RE.captures_iter(value) {
+        let whole = word.get(0).unwrap();
+        let between = &value[last..whole.start()];
+        let adjacent = pending.is_some() && between.trim().is_empty();
+
+        let charset = word.name("charset").unwrap().as_str();
+        let text = word["text"].as_bytes();
+        let bytes = match &word["encoding"] {
+            "B" | "b" => decode_base64(text)?,
+            _ => decode_q(text)?,
+        };
+
+        match &mut pending {
+            Some((pending_charset, pending_bytes)) if adjacent && pending_charset.eq_ignore_ascii_case(charset) => {
+                pending_bytes.extend(bytes);
+            },
+            _ => {
+                if let Some((pending_charset, pending_bytes)) = pending.take() {
+                    decoded.push_str(&decode_charset(pending_charset, &pending_bytes)?);
+                }
+                if !adjacent {
+                    decoded.push_str(between);
+                }
+                pending = Some((charset, bytes));
+            },
+        }
+        last = whole.end();
+    }
+    if let Some((charset, bytes)) = pending {
+        decoded.push_str(&decode_charset(charset, &bytes)?);
+    }
+    decoded.push_str(&value[last..]);
+    Ok(decoded)
+}
+
+// The Q encoding of RFC 2047: =XX hex escapes, with _ standing for a space. This is not
+// decode_quoted_printable: in a quoted-printable body _ is just _, and there are no soft line breaks
+// to remove from an encoded-word. A literal _ in a Q word is written =5F.
+fn decode_q(input: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut decoded = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        match input[i] {
+            b'_' => decoded.push(b' '),
+            b'=' => {
+                decoded.push(hex_byte(input.get(i + 1..i + 3).ok_or(Error::ParseError)?)?);
+                i += 2;
+            },
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    Ok(decoded)
+}
+
+fn hex_byte(hex: &[u8]) -> Result<u8, Error> {
+    let hex = std::str::from_utf8(hex).map_err(|_| Error::ParseError)?;
+    u8::from_str_radix(hex, 16).map_err(|_| Error::ParseError)
+}
+
+// Decode an RFC 2231 extended parameter value (charset'language'percent-encoded-text).
+pub(crate) fn decode_extended_value(value: &str) -> Result<String, Error> {
+    let (charset, text) = split_extended_value(value)?;
+    decode_charset(charset, &decode_percent(text)?)
+}
+
+// Split an RFC 2231 extended parameter value into its charset and still-encoded text. The
+// language is not needed to decode the value.
+pub(crate) fn split_extended_value(value: &str) -> Result<(&str, &str), Error> {
+    let mut fields = value.splitn(3, '\'');
+    match (fields.next(), fields.next(), fields.next()) {
+        (Some(charset), Some(_language), Some(text)) => Ok((charset, text)),
+        _ => Err(Error::ParseError),
+    }
+}
+
+// Decode the %XX escapes of an RFC 2231 extended parameter value.
+pub(crate) fn decode_percent(text: &str) -> Result<Vec<u8>, Error> {
+    let input = text.as_bytes();
+    let mut bytes = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        match input[i] {
+            b'%' => {
+                bytes.push(hex_byte(input.get(i + 1..i + 3).ok_or(Error::ParseError)?)?);
+                i += 2;
+            },
+            b => bytes.push(b),
+        }
+        i += 1;
+    }
+    Ok(bytes)
+}
+
+// Convert bytes in the named charset to a String.
+pub(crate) fn decode_charset(charset: &str, bytes: &[u8]) -> Result<String, Error> {
+    // RFC 2231 allows a language suffix, e.g. UTF-8*en
+    let charset = charset.split('*').next().unwrap_or("").trim().to_lowercase();
+    match charset.as_str() {
+        "iso-8859-1" | "iso8859-1" | "latin1" | "l1" => Ok(bytes.iter().map(|&b| b as char).collect()),
+        "windows-1252" | "cp1252" | "x-cp1252" => Ok(bytes.iter().map(|&b| cp1252_char(b)).collect()),
+        // UTF-8 is a superset of ASCII, and the best guess for anything unknown
+        _ => String::from_utf8(bytes.to_vec()).map_err(|_| Error::InvalidString),
+    }
+}
+
+// Windows-1252 is ISO-8859-1 with printable characters in place of most of the C1 controls
+// (0x80 to 0x9F). The five bytes it leaves undefined map to the control characters, as browsers do.
+fn cp1252_char(byte: u8) -> char {
+    const C1: [char; 32] = [
+        '\u{20ac}', '\u{0081}', '\u{201a}', '\u{0192}', '\u{201e}', '\u{2026}', '\u{2020}', '\u{2021}',
+        '\u{02c6}', '\u{2030}', '\u{0160}', '\u{2039}', '\u{0152}', '\u{008d}', '\u{017d}', '\u{008f}',
+        '\u{0090}', '\u{2018}', '\u{2019}', '\u{201c}', '\u{201d}', '\u{2022}', '\u{2013}', '\u{2014}',
+        '\u{02dc}', '\u{2122}', '\u{0161}', '\u{203a}', '\u{0153}', '\u{009d}', '\u{017e}', '\u{0178}',
+    ];
+    match byte {
+        0x80..=0x9f => C1[(byte - 0x80) as usize],
+        _ => byte as char,
+    }
+}
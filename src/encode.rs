@@ -0,0 +1,57 @@
+//! Encoders for MIME header values.
+
+use regex::Regex;
+
+// Longest run of UTF-8 bytes in one encoded-word. 45 bytes encode to 60 base64 characters,
+// keeping the word within the 75 character limit of RFC 2047.
+const MAX_WORD_BYTES: usize = 45;
+
+// Replace the non-ASCII words in a header value with RFC 2047 encoded-words. Words are split on
+// whitespace and structural characters, so parameter syntax, quotes and addresses are left
+// untouched; only the text is encoded. Consecutive non-ASCII words are encoded together.
+pub(crate) fn encode_non_ascii(value: &str) -> String {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r#"[^\s"<>()=;,]+"#).unwrap();
+    }
+
+    // Merge non-ASCII words separated only by whitespace into runs
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+    let mut previous_ascii = true;
+    for word in RE.find_iter(value) {
+        if word.as_str().is_ascii() {
+            previous_ascii = true;
+            continue;
+        }
+        match runs.last_mut() {
+            Some(run) if !previous_ascii && value[run.1..word.start()].trim().is_empty() => run.1 = word.end(),
+            _ => runs.push((word.start(), word.end())),
+        }
+        previous_ascii = false;
+    }
+
+    let mut encoded = String::with_capacity(value.len());
+    let mut last = 0;
+    for (start, end) in runs {
+        encoded.push_str(&value[last..start]);
+        encoded.push_str(&encode_words(&value[start..end]));
+        last = end;
+    }
+    encoded.push_str(&value[last..]);
+    encoded
+}
+
+// Encode text as one or more space-separated =?UTF-8?B?...?= words. Words are split on
+// character boundaries, as RFC 2047 forbids splitting a multi-byte character across words.
+fn encode_words(text: &str) -> String {
+    let mut words = Vec::new();
+    let mut start = 0;
+    while start < text.len() {
+        let mut end = (start + MAX_WORD_BYTES).min(text.len());
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        words.push(format!("=?UTF-8?B?{}?=", base64::encode(&text[start..end])));
+        start = end;
+    }
+    words.join(" ")
+}
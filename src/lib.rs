@@ -3,7 +3,8 @@
 //! A robust, minimal library for parsing MIME documents.
 //!
 //! Supports UTF-8, multipart documents, and nested documents. `crumble` assumes input is mostly
-//! compliant and tries to parse input as best as possible.
+//! compliant and tries to parse input as best as possible. Input which can't be parsed at all is
+//! an error, or with [`Message::new_lenient`] a message holding the input as plain text.
 //!
 //! Output is a minimal AST-like structure, with no filtering. This structure should be further
 //! processed to produce useful work. See for example [`crinkle`][1]. 
@@ -11,17 +12,31 @@
 //! # Example
 //!
 //! ```
+//! # fn load_mime_file() -> String {
+//! #     String::from("From: user@example.com\nContent-Type: text/plain\n\nHello, world!")
+//! # }
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
 //! use crumble::Message;
 //! let message = load_mime_file(); // Example! The consumer must have some source of documents
 //! let message = Message::new(&message)?; // Handle errors
+//! # Ok(())
+//! # }
 //! ```
 //! [1]: https://git.sr.ht/~happy_shredder/crinkle
 
-#![feature(test)]
+#![cfg_attr(test, feature(test))]
 #[cfg(test)] mod tests;
 #[macro_use] extern crate lazy_static;
 
+pub mod address;
+pub mod decode;
+mod encode;
+#[cfg(feature = "serde")] mod serde_base64;
+#[cfg(feature = "testutil")] pub mod testutil;
+
+use chrono::{DateTime, FixedOffset};
 use regex::Regex;
+use std::collections::HashMap;
 use std::fmt::Write;
 
 /// Internal error type.
@@ -29,16 +44,44 @@ use std::fmt::Write;
 /// # Remarks
 /// This library is permissive and tries to parse input as best as it can.
 /// Hence very few errors are possible. Trying to parse non-MIME documents is undefined behaviour.
-#[derive(Debug,PartialEq)]
+#[derive(Debug)]
 pub enum Error {
     Unknown,
     InvalidString,
     ParseError,
+    /// A plain document has no blank line separating the headers from the body. The input is
+    /// probably not a MIME document at all.
+    MissingSeparator,
+    /// A plain document has a body, but no headers before the blank line.
+    EmptyHeaders,
+    /// The document is longer than [`ParseOptions::max_len`], so it was not parsed.
+    TooLarge,
+    /// The document could not be read, as opposed to parsed.
+    Io(std::io::Error),
+}
+
+impl PartialEq for Error {
+    fn eq(&self, other: &Error) -> bool {
+        match (self, other) {
+            (Error::Unknown, Error::Unknown) => true,
+            (Error::InvalidString, Error::InvalidString) => true,
+            (Error::ParseError, Error::ParseError) => true,
+            (Error::MissingSeparator, Error::MissingSeparator) => true,
+            (Error::EmptyHeaders, Error::EmptyHeaders) => true,
+            (Error::TooLarge, Error::TooLarge) => true,
+            (Error::Io(a), Error::Io(b)) => a.kind() == b.kind(),
+            _ => false,
+        }
+    }
 }
 
 impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        Some(self)
+        match self {
+            Error::Io(e) => Some(e),
+            // The remaining errors are raised by this library and have no underlying cause
+            _ => None,
+        }
     }
 }
 
@@ -48,15 +91,47 @@ impl std::fmt::Display for Error {
             Error::Unknown => write!(f, "Error parsing message: Unknown error"),
             Error::InvalidString => write!(f, "Error parsing message: Invalid string"),
             Error::ParseError => write!(f, "Error parsing message: Invalid document"),
+            Error::MissingSeparator => write!(f, "Error parsing message: No blank line between headers and body"),
+            Error::EmptyHeaders => write!(f, "Error parsing message: No headers"),
+            Error::TooLarge => write!(f, "Error parsing message: Document too large"),
+            Error::Io(e) => write!(f, "Error reading message: {}", e),
         }
     }
 }
 
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
 /// Wraps a String tuple for more literate usage and application of traits.
-#[derive(Debug,PartialEq)]
+///
+/// Keys keep the case they were written in (e.g. `Message-ID`), but header names are
+/// case-insensitive, so headers are compared ignoring the case of their keys. Lookups such as
+/// [`Message::header`] also ignore case.
+///
+/// A parsed header records the [`Span`] of the document it was parsed from, running from the
+/// start of the key to the end of the value, excluding the line break which ends it. Headers
+/// constructed directly have a default span. Spans are not considered when comparing headers.
+///
+/// A header parsed with [`ParseOptions::preserve_header_bytes`], or constructed with
+/// [`Header::new_raw`], also keeps `raw`: the header exactly as it appeared, from the start of the
+/// key to the end of the line break ending the value. The key and value are normalised as usual,
+/// so lookups and accessors behave the same either way. Nor is `raw` considered when comparing.
+#[derive(Debug,Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Header {
     pub key: String,
     pub value: String,
+    pub raw: Option<String>,
+    pub span: Span,
+}
+
+impl PartialEq for Header {
+    fn eq(&self, other: &Header) -> bool {
+        self.key.eq_ignore_ascii_case(&other.key) && self.value == other.value
+    }
 }
 
 impl Header {
@@ -64,379 +139,2770 @@ impl Header {
         Header {
             key: key.to_string(),
             value: value.to_string(),
+            raw: None,
+            span: Span::default(),
         }
     }
-}
 
-impl std::string::ToString for Header {
-    fn to_string(&self) -> String {
-        format!("{}: {}", self.key, self.value)
+    /// Construct a header from the text either side of its `:`, keeping that text in `raw`.
+    ///
+    /// Signature verification (e.g. DKIM) needs the header bytes as they appeared on the wire,
+    /// including folding whitespace and line endings. The key and value are trimmed, and CRLF
+    /// line endings in a folded value become LF, as when parsing.
+    pub fn new_raw(key: &str, value: &str) -> Header {
+        Header {
+            key: key.trim().to_string(),
+            value: value.trim().replace("\r\n", "\n"),
+            raw: Some(format!("{}:{}", key, value)),
+            span: Span::default(),
+        }
     }
 }
 
-trait Search {
-    fn search(&self, needle: &str) -> Option<String>;
-}
+impl Header {
+    /// The value as a single line, for display.
+    ///
+    /// Each line break in a folded value, along with the whitespace starting the continuation
+    /// line, becomes a single space. The value itself is left as parsed, since signature checks
+    /// (e.g. DKIM) need the folding intact.
+    pub fn unfolded(&self) -> String {
+        unfold(&self.value)
+    }
 
-impl Search for Vec<Header> {
-    fn search(&self, needle: &str) -> Option<String> {
-        for pair in self {
-            if pair.key.to_lowercase() == needle.to_lowercase() {
-                return Some(pair.value.to_string())
-            }
-        }
-        None
+    /// The value with any RFC 2047 encoded-words (e.g. `=?UTF-8?B?...?=`) decoded.
+    ///
+    /// Text outside encoded-words is returned unchanged. Returns `Error::InvalidString` if an
+    /// encoded-word can't be converted from its declared charset.
+    pub fn decoded_value(&self) -> Result<String, Error> {
+        decode::decode_encoded_words(&self.value)
     }
 }
 
-trait Boundary {
-    fn boundary(&self) -> Option<String>;
+impl std::fmt::Display for Header {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.key, self.value)
+    }
 }
 
-impl Boundary for Vec<Header> {
+/// An attachment's filename, if it has one, and decoded contents. See [`Message::attachments`].
+pub type Attachment = (Option<String>, Vec<u8>);
+
+/// The headers of a message or multipart section, in the order they appear.
+///
+/// Lookups by name ignore case, as header names are case-insensitive. The underlying `Vec` is
+/// reachable through `Deref`, so headers can be pushed, removed or indexed by position as usual.
+///
+/// ```
+/// use crumble::Message;
+/// let message = Message::new("Received: from a\nReceived: from b\nSubject: Hi\n\nHello").unwrap();
+/// assert_eq!(message.headers.get("subject"), Some("Hi"));
+/// assert_eq!(&message.headers["Subject"], "Hi");
+/// assert_eq!(message.headers.get_all("Received").collect::<Vec<&str>>(), vec!["from a", "from b"]);
+/// assert!(!message.headers.contains("To"));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(transparent))]
+pub struct Headers(Vec<Header>);
+
+impl Headers {
+    const EMPTY: &'static Headers = &Headers(Vec::new());
+
+    /// An empty set of headers.
+    pub const fn new() -> Headers {
+        Headers(Vec::new())
+    }
+
+    /// The value of the first header named `key`, ignoring case.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0.iter()
+            .find(|header| header.key.eq_ignore_ascii_case(key))
+            .map(|header| header.value.as_str())
+    }
+
+    /// The values of every header named `key`, ignoring case, in the order they appear.
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.0.iter()
+            .filter(move |header| header.key.eq_ignore_ascii_case(key))
+            .map(|header| header.value.as_str())
+    }
+
+    /// Whether there is a header named `key`, ignoring case.
+    pub fn contains(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Iterate over the headers in order.
+    pub fn iter(&self) -> std::slice::Iter<'_, Header> {
+        self.0.iter()
+    }
+
+    // The multipart boundary from the Content-Type header, if it has a usable one. This is the
+    // same boundary parsing splits on, so serialised messages are delimited as they were read.
     fn boundary(&self) -> Option<String> {
-        match self.search("Content-Type") {
-            None => return None,
-            Some(ct) => {
-                // https://tools.ietf.org/html/rfc1521#page-10
-                // First split off the type/subtype from the parameters
-                let tmp: Vec<&str> = ct.splitn(2, ";").collect();
-
-                // Now search the parameters
-                if tmp.len() > 1 {
-                    let tmp = tmp[1];
-                    let tmp: Vec<&str> = tmp.split(";").collect();
-                    for p in tmp {
-                        let parameter: Vec<&str> = p.split("=").collect();
-                        let key = parameter[0].trim().to_lowercase();
-                        let value = parameter[1].trim();
-                        let value = value.replace("\"", ""); // strip quotes
-                        let value = value.trim();
-
-                        if key == "boundary" {
-                            return Some(value.to_string())
-                        }
-                    }
-                }
-                return None
-            }
-        }
+        boundary_param(self.get("Content-Type")?)
     }
 }
 
-// Wrapper to avoid error.
-trait ToString {
-    fn to_string(&self) -> String;
+impl std::ops::Deref for Headers {
+    type Target = Vec<Header>;
+
+    fn deref(&self) -> &Vec<Header> {
+        &self.0
+    }
 }
 
-impl ToString for Vec<Header> {
-    fn to_string(&self) -> String {
-        let tmp: Vec<String> = self.iter().map(|x| x.to_string()).collect();
-        let tmp = tmp.join("\n");
-        String::from(tmp)
+impl std::ops::DerefMut for Headers {
+    fn deref_mut(&mut self) -> &mut Vec<Header> {
+        &mut self.0
     }
 }
 
-/// Representation of a section of a MIME document.
+/// The value of the first header named `key`, ignoring case.
 ///
-/// MIME sections can be some text; a header and some text or data; or nested combinations.
-/// This parser may produce empty sections.
-#[derive(Debug, PartialEq)]
-pub enum Section {
-    Plain {body: Vec<u8>},
-    Multipart {
-        headers: Vec<Header>,
-        body: Vec<Box<Section>>,
-    },
-    Empty,
+/// # Panics
+/// Panics if there is no such header. Use [`Headers::get`] when it may be missing.
+impl std::ops::Index<&str> for Headers {
+    type Output = str;
+
+    fn index(&self, key: &str) -> &str {
+        self.get(key).unwrap_or_else(|| panic!("no header named {}", key))
+    }
 }
 
-impl std::string::ToString for Section {
-    fn to_string(&self) -> String {
-        let mut section_string = String::new();
-        match self {
-            Section::Plain {body} => write!(&mut section_string, "{}", std::str::from_utf8(body).unwrap()).expect("Error constructing string."),
-            Section::Multipart {headers, body} => {
-                write!(&mut section_string, "{}\n", headers.to_string()).expect("Error constructing string.");
-                let boundary = headers.boundary();
-                match &boundary {
-                    None => (),
-                    Some(b) => write!(&mut section_string, "--{}", b).expect("Error constructing string."),
-                };
-                for section in body {
-                    write!(&mut section_string, "\n{}\n", section.to_string()).expect("Error constructing string.");
-                    match &boundary {
-                        None => (),
-                        Some(b) => write!(&mut section_string, "--{}", b).expect("Error constructing string."),
-                    };
-                }
-                match &boundary {
-                    None => (),
-                    Some(_b) => write!(&mut section_string, "--").expect("Error constructing string."),
-                };
-            },
-            Section::Empty => (),
-        }
-        section_string
+impl std::ops::Index<usize> for Headers {
+    type Output = Header;
+
+    fn index(&self, index: usize) -> &Header {
+        &self.0[index]
     }
 }
 
-impl Section {
-    pub fn new(raw_section: &str) -> Result<Section, Box<dyn std::error::Error + 'static>> {
-        // A section can either be just some plain text, or be split into headers/body.
-        // That body is also section.
-        // If the raw section has no headers, return it as plain
-        // If it has headers, split off the headers and recurse
+impl std::ops::IndexMut<usize> for Headers {
+    fn index_mut(&mut self, index: usize) -> &mut Header {
+        &mut self.0[index]
+    }
+}
 
-        // Catch leftover from multipart parsing
-        if raw_section == "--\n" {
-            return Ok(Section::Empty);
-        }
-        if raw_section == "--" {
-            return Ok(Section::Empty);
-        }
+impl PartialEq<Vec<Header>> for Headers {
+    fn eq(&self, other: &Vec<Header>) -> bool {
+        self.0 == *other
+    }
+}
 
-        // A section with headers has a different parsing pipeline than one without.
-        if Section::has_headers(raw_section)? {
-            Section::parse_multipart(raw_section)
-        } else {
-            Ok(Section::Plain {body: raw_section.as_bytes().to_vec()})
-        }
+impl From<Vec<Header>> for Headers {
+    fn from(headers: Vec<Header>) -> Headers {
+        Headers(headers)
     }
+}
 
-    fn has_headers(raw_message: &str) -> Result<bool, Box<dyn std::error::Error + 'static>> {
-        // If there are headers there should be a content-type
-        // Note that headers may be separated by a boundary (nested sections) or newlines (not
-        // nested)
-        lazy_static! {
-            static ref RE: Regex = Regex::new(r"(Content-Type|Content-type|content-type): .+?").unwrap();
-        }
-        // Performance: Assume that the header is not too long and the boundary appears early
-        if raw_message.len() > 3000 {
-            Ok(RE.is_match(&raw_message[0..3000]))
-        } else {
-            Ok(RE.is_match(raw_message))
-        }
+impl From<Headers> for Vec<Header> {
+    fn from(headers: Headers) -> Vec<Header> {
+        headers.0
     }
+}
 
-    fn has_boundary(raw_message: &str) -> Result<bool, Box<dyn std::error::Error + 'static>> {
-        lazy_static! {
-            static ref RE: Regex = Regex::new(r"(boundary|Boundary)=.+?").unwrap();
-        }
-        // Performance: Assume that the header is not too long and the boundary appears early
-        if raw_message.len() > 3000 {
-            Ok(RE.is_match(&raw_message[0..3000]))
-        } else {
-            Ok(RE.is_match(raw_message))
-        }
+impl std::iter::FromIterator<Header> for Headers {
+    fn from_iter<I: IntoIterator<Item = Header>>(iter: I) -> Headers {
+        Headers(iter.into_iter().collect())
     }
+}
 
-    fn parse_multipart(raw_section: &str) -> Result<Section, Box<dyn std::error::Error + 'static>> {
-        // The body can contain a series of (possibly nested) sections
-        // So check for a boundary.
-        // If there is a boundary, split the body and iterate.
-        // Otherwise, just return a single-entry Vec
+impl IntoIterator for Headers {
+    type Item = Header;
+    type IntoIter = std::vec::IntoIter<Header>;
 
-        if Section::has_boundary(raw_section)? {
-            lazy_static! {
-                static ref RE: Regex = Regex::new(r#"(?m)(boundary|Boundary)=("|')?(?P<boundary>([[:alnum:]]|[-_=+,.<>])+)("|')?"#).unwrap();
-            }
-            let boundary = match RE.captures(raw_section) {
-                Some(c) => c["boundary"].to_string(),
-                None => return Err(Box::new(Error::InvalidString))
-            };
-            if boundary.len() == 0 {
-                return Err(Box::new(Error::ParseError));
-            }
-            // Each section is separated by --<boundary>, and finishes with --<boundary>--
-            let boundary = format!("--{}", boundary);
-            let raw_sections: Vec<&str> = raw_section.split(boundary.as_str()).collect();
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
 
-            let raw_headers = raw_sections[0];
-            let headers = parse_headers(raw_headers)?;
+impl<'a> IntoIterator for &'a Headers {
+    type Item = &'a Header;
+    type IntoIter = std::slice::Iter<'a, Header>;
 
-            let mut sections = Vec::new();
-            let raw_sections = &raw_sections[1..raw_sections.len() - 1]; // Drop empty section at tail
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
 
-            for section in raw_sections {
-                // Recursively construct sections
-                let section = Section::new(&section)?;
-                sections.push(Box::new(section));
-            }
+impl<'a> IntoIterator for &'a mut Headers {
+    type Item = &'a mut Header;
+    type IntoIter = std::slice::IterMut<'a, Header>;
 
-            Ok(Section::Multipart {
-                headers: headers,
-                body: sections,
-            })
-        } else {
-            // Separate out headers
-            lazy_static! {
-                static ref RE: Regex = Regex::new(r"\n{2,}|\r{2,}|(\r\n){2,}|(\n\r){2,}").unwrap();
-            }
-            let split: Vec<&str> = RE.splitn(raw_section, 2).collect();
-            let raw_headers = split[0];
-            let headers = parse_headers(raw_headers)?;
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter_mut()
+    }
+}
 
-            // Process body
-            let body = split[1];
-            let body = Section::new(&body)?;
-            let sections = vec![Box::new(body)];
+/// The header block: each header on its own line, as written by [`Message::to_bytes`].
+impl std::fmt::Display for Headers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format_headers(self))
+    }
+}
 
-            Ok(Section::Multipart {
-                headers: headers,
-                body: sections,
-            })
-        }
+/// A parsed `Content-Disposition` header value.
+///
+/// See [RFC 2183](https://tools.ietf.org/html/rfc2183).
+#[derive(Debug, PartialEq, Clone)]
+pub struct ContentDisposition {
+    /// The lowercased disposition type, usually `inline` or `attachment`.
+    pub disposition: String,
+    /// Parameters in the order they appear. Keys are lowercased and values unquoted.
+    pub params: Vec<(String, String)>,
+}
+
+impl ContentDisposition {
+    /// Parse the value of a `Content-Disposition` header. Folded values are accepted.
+    pub fn parse(value: &str) -> Result<ContentDisposition, Error> {
+        let value = unfold(value);
+        let disposition = match split_unquoted(&value, |c| c == ';' || c.is_whitespace()).first() {
+            Some(disposition) if !disposition.contains('=') => disposition.to_lowercase(),
+            _ => return Err(Error::ParseError),
+        };
+
+        Ok(ContentDisposition {
+            disposition,
+            params: parse_parameters(&value),
+        })
+    }
+
+    /// The value of the named parameter, if present.
+    ///
+    /// If the parameter is repeated the last occurrence wins, as in common mail clients. All
+    /// occurrences remain available in `params`.
+    pub fn param(&self, key: &str) -> Option<&str> {
+        self.params.iter()
+            .rfind(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The value of the named parameter, decoded.
+    ///
+    /// RFC 2231 forms are preferred over a plain `key` parameter: first a charset-tagged `key*`,
+    /// then continuations (`key*0`, `key*1*`, ...) joined in order. RFC 2047 encoded-words in a
+    /// plain `key` are decoded, and a value which can't be decoded is returned as given.
+    pub fn decoded_param(&self, key: &str) -> Option<String> {
+        decoded_param(&self.params, key)
+    }
+
+    /// The suggested filename for the content, decoded as with
+    /// [`ContentDisposition::decoded_param`].
+    pub fn filename(&self) -> Option<String> {
+        self.decoded_param("filename")
+    }
+
+    /// The approximate size of the file in bytes, from the `size` parameter.
+    pub fn size(&self) -> Option<u64> {
+        self.param("size")?.trim().parse().ok()
+    }
+
+    /// The `creation-date` parameter.
+    pub fn creation_date(&self) -> Option<DateTime<FixedOffset>> {
+        self.date("creation-date")
+    }
+
+    /// The `modification-date` parameter.
+    pub fn modification_date(&self) -> Option<DateTime<FixedOffset>> {
+        self.date("modification-date")
+    }
+
+    /// The `read-date` parameter.
+    pub fn read_date(&self) -> Option<DateTime<FixedOffset>> {
+        self.date("read-date")
+    }
+
+    // Dates are RFC 822 date-times. Unparseable dates are treated as missing.
+    fn date(&self, key: &str) -> Option<DateTime<FixedOffset>> {
+        DateTime::parse_from_rfc2822(self.param(key)?.trim()).ok()
     }
 }
 
-/// Representation of a MIME document.
+/// A parsed `Content-Type` header value.
 ///
-/// MIME documents have a large initial key-value header, followed by one or more text/data sections.
-/// A section can be some plain text; a header with text or data; or some nested combination.
-#[derive(Debug)]
-pub struct Message {
-    pub headers: Vec<Header>,
-    pub sections: Vec<Section>,
+/// See [RFC 2045](https://tools.ietf.org/html/rfc2045#section-5).
+#[derive(Debug, PartialEq, Clone)]
+pub struct ContentType {
+    /// The lowercased top-level type, e.g. `text` or `multipart`.
+    pub mime_type: String,
+    /// The lowercased subtype, e.g. `plain` or `alternative`.
+    pub subtype: String,
+    /// Parameters in the order they appear. Keys are lowercased and values unquoted.
+    pub params: Vec<(String, String)>,
 }
 
-impl std::string::ToString for Message {
-    fn to_string(&self) -> String {
-        let mut message = String::new();
-        write!(&mut message, "{}\n\n", self.headers.to_string()).expect("Error constructing string.");
-        let boundary = self.headers.boundary();
-        match &boundary {
-            None => (),
-            Some(b) => write!(&mut message, "--{}", b).expect("Error constructing string."),
+impl ContentType {
+    /// Parse the value of a `Content-Type` header. Folded values are accepted.
+    pub fn parse(value: &str) -> Result<ContentType, Error> {
+        let value = unfold(value);
+        let media_type = match split_unquoted(&value, |c| c == ';' || c.is_whitespace()).first() {
+            Some(media_type) if !media_type.contains('=') => media_type.to_lowercase(),
+            _ => return Err(Error::ParseError),
         };
-        for section in &self.sections {
-            write!(&mut message, "\n{}\n", section.to_string()).expect("Error constructing string.");
-            match &boundary {
-                None => (),
-                Some(b) => write!(&mut message, "--{}", b).expect("Error constructing string."),
-            };
-        }
-        match boundary {
-            None => (),
-            Some(_b) => write!(&mut message, "--").expect("Error constructing string."),
+        let (mime_type, subtype) = match media_type.split_once('/') {
+            Some((mime_type, subtype)) if !mime_type.is_empty() && !subtype.is_empty() => (mime_type, subtype),
+            _ => return Err(Error::ParseError),
+        };
+
+        Ok(ContentType {
+            mime_type: mime_type.to_string(),
+            subtype: subtype.to_string(),
+            params: parse_parameters(&value),
+        })
+    }
+
+    /// True if this is the given type and subtype, ignoring case.
+    pub fn is(&self, mime_type: &str, subtype: &str) -> bool {
+        self.mime_type.eq_ignore_ascii_case(mime_type) && self.subtype.eq_ignore_ascii_case(subtype)
+    }
+
+    /// True if this matches a `type/subtype` pattern, ignoring case.
+    ///
+    /// A trailing `*` matches any rest of the subtype, so `image/*` matches every image and
+    /// `application/vnd.*` every vendor type. A type of `*` matches any type.
+    pub fn matches(&self, pattern: &str) -> bool {
+        let (mime_type, subtype) = match pattern.split_once('/') {
+            Some((mime_type, subtype)) => (mime_type.trim(), subtype.trim()),
+            None => return false,
+        };
+        let type_matches = mime_type == "*" || self.mime_type.eq_ignore_ascii_case(mime_type);
+        let subtype_matches = match subtype.strip_suffix('*') {
+            Some(prefix) => self.subtype.get(..prefix.len()).is_some_and(|start| start.eq_ignore_ascii_case(prefix)),
+            None => self.subtype.eq_ignore_ascii_case(subtype),
         };
-        message
+        type_matches && subtype_matches
+    }
+
+    /// The value of the named parameter, if present.
+    ///
+    /// If the parameter is repeated the last occurrence wins, as with
+    /// [`ContentDisposition::param`].
+    pub fn param(&self, key: &str) -> Option<&str> {
+        self.params.iter()
+            .rfind(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The value of the named parameter, decoded as with [`ContentDisposition::decoded_param`].
+    pub fn decoded_param(&self, key: &str) -> Option<String> {
+        decoded_param(&self.params, key)
     }
 }
 
-impl Message {
-    /// Parse a MIME document and return structured representation.
-    /// Performance should be reasonable: provided tests take between 5 and 200μs per document.
-    pub fn new(raw_message: &str) -> Result<Message, Box<dyn std::error::Error + 'static>> {
-        // Multipart and plain messages require entirely different parsing pathways
-        if Message::is_multipart(raw_message)? {
-            Message::parse_multipart(raw_message)
-        } else {
-            Message::parse_plain(raw_message)
+/// Options controlling how a document is parsed.
+///
+/// The defaults match the behaviour of [`Message::new`].
+#[derive(Debug, Clone)]
+pub struct ParseOptions {
+    /// Keep each header byte-exact in [`Header::raw`], as with [`Header::new_raw`].
+    ///
+    /// Keys and values are trimmed, and CRLF line endings in folded values become LF, whether or
+    /// not the raw bytes are kept. Keys keep the case they were written in.
+    pub preserve_header_bytes: bool,
+    /// Keep a copy of the document in the parsed [`Message`].
+    ///
+    /// Sections which are unchanged since parsing are then written out exactly as they appeared,
+    /// so a message can be edited and forwarded without altering the parts that weren't touched.
+    pub retain_source: bool,
+    /// The deepest nesting of parts to parse before giving up with `Error::ParseError`.
+    ///
+    /// Parts are parsed recursively, so without a limit a document with thousands of nested parts
+    /// would overflow the stack. Defaults to 100, far deeper than any real message.
+    pub max_depth: usize,
+    /// How many bytes at the start of a part are searched for the headers which mark it as having
+    /// headers (`Content-Type` or `Content-Transfer-Encoding`). Defaults to 3000.
+    ///
+    /// Headers are assumed to be short, so the body of a part without headers isn't searched in
+    /// full. Raise the limit for parts with very long header blocks.
+    pub header_scan_limit: usize,
+    /// The longest document, in bytes, to parse. Longer documents are rejected with
+    /// `Error::TooLarge` before any parsing is done.
+    ///
+    /// Parsing allocates in proportion to the size of the document, so set a limit when parsing
+    /// untrusted input. Defaults to `None`, for no limit.
+    pub max_len: Option<usize>,
+}
+
+impl Default for ParseOptions {
+    fn default() -> ParseOptions {
+        ParseOptions {
+            preserve_header_bytes: false,
+            retain_source: false,
+            max_depth: 100,
+            header_scan_limit: 3000,
+            max_len: None,
         }
     }
+}
+
+/// A parser configured once with [`ParseOptions`] and reused for many documents.
+///
+/// # Example
+///
+/// ```
+/// use crumble::{Parser, ParseOptions};
+/// let parser = Parser::new(ParseOptions { preserve_header_bytes: true, ..Default::default() });
+/// for raw in &["Subject: one\n\nFirst", "Subject: two\n\nSecond"] {
+///     let message = parser.parse(raw).unwrap();
+///     assert_eq!(message.headers[0].key, "Subject");
+/// }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Parser {
+    options: ParseOptions,
+}
 
-    fn is_multipart(raw_message: &str) -> Result<bool, Box<dyn std::error::Error + 'static>> {
-        lazy_static! {
-            static ref RE: Regex = Regex::new(r"(Content-Type|Content-type|content-type): multipart.+?").unwrap();
+impl Parser {
+    pub fn new(options: ParseOptions) -> Parser {
+        Parser {
+            options,
         }
-        Ok(RE.is_match(&raw_message))
     }
 
-    fn parse_plain(raw_message: &str) -> Result<Message, Box<dyn std::error::Error + 'static>> {
-        // Plain messages separate the headers from the body with more than 2 newlines
-        lazy_static! {
-            static ref RE: Regex = Regex::new(r"\n{2,}|\r{2,}|(\r\n){2,}|(\n\r){2,}").unwrap();
-        }
-        let split: Vec<&str> = RE.splitn(raw_message, 2).collect();
+    /// The options this parser was configured with.
+    pub fn options(&self) -> &ParseOptions {
+        &self.options
+    }
+
+    /// Parse a MIME document, as with [`Message::with_options`].
+    pub fn parse(&self, raw_message: &str) -> Result<Message, Error> {
+        Message::with_options(raw_message, &self.options)
+    }
 
-        if split.len() != 2 || split[0].len() == 0 || split[1].len() == 0 {
-            return Err(Box::new(Error::InvalidString));
+    /// Read a MIME document from a reader and parse it, as with [`Message::from_reader`].
+    ///
+    /// With a [`ParseOptions::max_len`], no more than one byte past the limit is read, and a longer
+    /// document is rejected with `Error::TooLarge`.
+    pub fn parse_reader<R: std::io::Read>(&self, reader: R) -> Result<Message, Error> {
+        Message::read(reader, &self.options)
+    }
+}
+
+// Find the blank line separating headers from a body, returning its start and end. Some
+// generators use bare \r line endings. This is the first run of two or more \n, \r, \r\n or \n\r
+// line endings, the same in the order listed, so a run like \n\r\n is only its first \n\r.
+//
+// Every document is split here, so it is scanned by hand rather than with a regex.
+fn find_blank_line(raw: &[u8]) -> Option<(usize, usize)> {
+    // The length of the run of line endings at the start of the input, if it is a blank line
+    let run = |rest: &[u8], line_ending: &[u8]| {
+        let count = rest.chunks_exact(line_ending.len()).take_while(|chunk| *chunk == line_ending).count();
+        (count >= 2).then_some(count * line_ending.len())
+    };
+    let mut start = 0;
+    while let Some(offset) = raw[start..].iter().position(|&b| b == b'\n' || b == b'\r') {
+        start += offset;
+        let rest = &raw[start..];
+        let len = match rest[0] {
+            b'\n' => run(rest, b"\n").or_else(|| run(rest, b"\n\r")),
+            _ => run(rest, b"\r").or_else(|| run(rest, b"\r\n")),
+        };
+        if let Some(len) = len {
+            return Some((start, start + len));
         }
+        start += 1;
+    }
+    None
+}
 
-        let raw_headers = split[0];
-        let headers = parse_headers(raw_headers)?;
+// Split a document or part into the headers and the body after the blank line, if there is one.
+fn split_blank_line(raw: &[u8]) -> (&[u8], Option<&[u8]>) {
+    match find_blank_line(raw) {
+        Some((start, end)) => (&raw[..start], Some(&raw[end..])),
+        None => (raw, None),
+    }
+}
 
-        // Everything after the header is by definition the body. There is only one section.
-        let tmp = split[1];
-        let sections = vec![Section::new(&tmp)?];
+// State shared by every level of a parse.
+struct Context<'a> {
+    source: &'a [u8],
+    options: &'a ParseOptions,
+    // How many parts enclose the one being parsed
+    depth: usize,
+}
 
-        Ok(Message {
-            headers: headers,
-            sections: sections,
+impl<'a> Context<'a> {
+    // The context for the parts nested in the one being parsed.
+    fn nested(&self) -> Result<Context<'a>, Error> {
+        if self.depth >= self.options.max_depth {
+            return Err(Error::ParseError);
+        }
+        Ok(Context {
+            depth: self.depth + 1,
+            ..*self
         })
     }
 
-    fn parse_multipart(raw_message: &str) -> Result<Message, Box<dyn std::error::Error + 'static>> {
-        // Multipart messages separate parts using a boundary string, defined in the main headers
-        // Any reasonable string after a `boundary="` is the boundary
-        lazy_static! {
-            static ref RE: Regex = Regex::new(r#"(?m)(boundary|Boundary)=("|')?(?P<boundary>([[:alnum:]]|[-_=+,.<>])+)("|')?"#).unwrap();
+    // Parse a block of headers from the source document, with spans relative to the document.
+    fn headers(&self, raw_headers: &[u8]) -> Result<Headers, Error> {
+        let offset = self.span(raw_headers).start;
+        let mut headers = parse_header_bytes(raw_headers, self.options)?;
+        for header in &mut headers {
+            header.span.start += offset;
+            header.span.end += offset;
         }
-        let b = match RE.captures(raw_message) {
-            Some(c) => c["boundary"].to_string(),
-            None => return Err(Box::new(Error::InvalidString)),
-        };
-        let boundary = format!("--{}", b);
-        let raw_parts: Vec<&str> = raw_message.split(boundary.as_str()).collect();
+        Ok(headers)
+    }
+
+    // The position of a slice of the source document.
+    fn span(&self, part: &[u8]) -> Span {
+        let start = part.as_ptr() as usize - self.source.as_ptr() as usize;
+        Span {
+            start,
+            end: start + part.len(),
+        }
+    }
+}
 
-        let raw_headers = raw_parts[0];
-        let headers = parse_headers(raw_headers)?;
+// Split a structured header value (e.g. Content-Type) into its key=value parameters.
+// Keys are lowercased and surrounding quotes are stripped from values.
+fn parse_parameters(value: &str) -> Vec<(String, String)> {
+    quoted_parameters(value).into_iter()
+        .map(|(key, value)| (key, unquote(value)))
+        .collect()
+}
 
-        let mut sections = Vec::new();
-        let raw_parts = &raw_parts[1..raw_parts.len()];
+// Split a structured header value into its key=value parameters, as for parse_parameters, but
+// leave the values as they were written.
+fn quoted_parameters(value: &str) -> Vec<(String, &str)> {
+    // Parameters follow the type/subtype and are separated by ;
+    // Some generators separate them with whitespace instead, so fall back to that when there is
+    // no ; but key=value tokens follow the type.
+    let raw_parameters = if has_unseparated_parameters(value) {
+        split_unquoted(value, |c| c.is_whitespace())
+    } else {
+        split_unquoted(value, |c| c == ';')
+    };
 
-        // Parse each section
-        for section in raw_parts {
-            let section = Section::new(section)?; // Note that this constructor will recursively build sections, as required
-            sections.push(section);
+    raw_parameters.iter().skip(1)
+        .filter_map(|p| {
+            let (key, value) = p.split_once('=')?;
+            let key = key.trim().to_lowercase();
+            (!key.is_empty()).then_some((key, value.trim()))
+        })
+        .collect()
+}
+
+// The value of a parameter, decoded from whichever of the RFC 2231 or plain forms is present.
+fn decoded_param(params: &[(String, String)], key: &str) -> Option<String> {
+    let value = |key: &str| params.iter().rfind(|(k, _)| k.eq_ignore_ascii_case(key)).map(|(_, v)| v.as_str());
+
+    if let Some(extended) = value(&format!("{}*", key)) {
+        if let Ok(decoded) = decode::decode_extended_value(extended) {
+            return Some(decoded);
         }
+    }
+    if let Some(joined) = join_continuations(params, key) {
+        return Some(joined);
+    }
+    let plain = value(key)?;
+    Some(decode::decode_encoded_words(plain).unwrap_or_else(|_| plain.to_string()))
+}
 
-        Ok(Message {
-            headers: headers,
-            sections: sections,
+// Join the RFC 2231 continuations of a parameter (key*0, key*1, ...). Sections ending in * are
+// percent-encoded, and the first of them gives the charset of the whole value. Numbering must
+// start at 0 and is followed until the first gap.
+fn join_continuations(params: &[(String, String)], key: &str) -> Option<String> {
+    let prefix = format!("{}*", key.to_lowercase());
+    let mut sections: Vec<(usize, bool, &str)> = params.iter()
+        .filter_map(|(k, v)| {
+            let index = k.strip_prefix(&prefix)?;
+            let (index, encoded) = match index.strip_suffix('*') {
+                Some(index) => (index, true),
+                None => (index, false),
+            };
+            Some((index.parse().ok()?, encoded, v.as_str()))
         })
+        .collect();
+    sections.sort_by_key(|(index, _, _)| *index);
+
+    let mut charset = "us-ascii";
+    let mut bytes = Vec::new();
+    for (expected, (index, encoded, value)) in sections.into_iter().enumerate() {
+        if index != expected {
+            break;
+        }
+        match (index, encoded) {
+            (0, true) => {
+                let (declared, text) = decode::split_extended_value(value).ok()?;
+                charset = declared;
+                bytes.extend(decode::decode_percent(text).ok()?);
+            },
+            (_, true) => bytes.extend(decode::decode_percent(value).ok()?),
+            (_, false) => bytes.extend(value.as_bytes()),
+        }
+    }
+    if bytes.is_empty() {
+        return None;
     }
+    decode::decode_charset(charset, &bytes).ok()
 }
 
-// Find keys and values for each header
-fn parse_headers(raw_headers: &str) -> Result<Vec<Header>, Box<dyn std::error::Error + 'static>> {
-    // A MIME key is a string of letters|numbers|-|_, followed by a :
-    // It starts on it's own line (i.e. after a \n)
-    // While the spec requires a header to be all on its own line,
-    // parsers in the wild (e.g. GMail) will split headers across mutliple lines.
-    // Hence, a value is everything between two keys.
-    // So, find the positions of all the keys -> extract key String
-    // Then, infer the position of text between subsequent keys -> extract value String
+// True if a structured header value has parameters separated by whitespace rather than ;
+fn has_unseparated_parameters(value: &str) -> bool {
+    if split_unquoted(value, |c| c == ';').len() > 1 {
+        return false;
+    }
+    split_unquoted(value, |c| c.is_whitespace())
+        .iter()
+        .skip(1)
+        .any(|token| token.contains('='))
+}
 
-    lazy_static! {
-        static ref RE: Regex = Regex::new(r"(?m)^[0-9A-Za-z_\-]+:").unwrap();
+// Split on the given separator, ignoring separators inside quoted strings. Empty pieces are dropped.
+fn split_unquoted(value: &str, separator: impl Fn(char) -> bool) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut quote = None;
+    let mut start = 0;
+    for (index, c) in value.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => (),
+            None if c == '"' => quote = Some(c),
+            None if separator(c) => {
+                pieces.push(&value[start..index]);
+                start = index + c.len_utf8();
+            },
+            None => (),
+        }
     }
-    let mut header_indices: Vec<(usize,usize)> = Vec::new();
+    pieces.push(&value[start..]);
+    pieces.into_iter().map(|p| p.trim()).filter(|p| !p.is_empty()).collect()
+}
 
-    // Construct a vector of key positions
-    for header in RE.find_iter(raw_headers) {
-        header_indices.push((header.start(), header.end() - 1)); // Strip off :
+// Strip a single pair of matching surrounding quotes. In a double-quoted string, a backslash
+// escapes the character after it (a quoted-pair, RFC 5322 section 3.2.4), so backslashes are
+// removed too. Single quotes aren't standard, but some generators use them, without escapes.
+pub(crate) fn unquote(value: &str) -> String {
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        let mut unquoted = String::with_capacity(value.len() - 2);
+        let mut chars = value[1..value.len() - 1].chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => unquoted.extend(chars.next()),
+                c => unquoted.push(c),
+            }
+        }
+        return unquoted;
     }
+    if value.len() >= 2 && value.starts_with('\'') && value.ends_with('\'') {
+        return value[1..value.len() - 1].to_string();
+    }
+    value.to_string()
+}
 
-    // Convert key positions to key and value strings
-    let mut headers: Vec<Header> = Vec::new();
-    for (index, header) in header_indices.iter().enumerate() {
-        let key = String::from(&raw_headers[header.0..header.1]);
-        let key = key.trim();
-        let key = String::from(key);
-        let key = key.to_lowercase();
-
-        // The final value is not between two keys: it is final key to end of string
-        if index < header_indices.len() - 1 {
-            let value = String::from(&raw_headers[header.1 + 2..header_indices[index + 1].0]);  // Correct for :
-            let value = value.trim();
-            headers.push(Header::new(&key, &value));
-        } else {
-            let value = String::from(&raw_headers[header.1 + 2..]);
-            let value = value.trim();
-            headers.push(Header::new(&key, &value));
+// A count with its noun, pluralised: "1 header", "2 headers".
+fn count(n: usize, noun: &str) -> String {
+    format!("{} {}{}", n, noun, if n == 1 { "" } else { "s" })
+}
+
+// Join headers into a header block.
+fn format_headers(headers: &Headers) -> String {
+    let tmp: Vec<String> = headers.iter().map(Header::to_string).collect();
+    tmp.join("\n")
+}
+
+// Write a header block and the blank line after it for Message::to_bytes, with the given line
+// ending. Folded headers have long lines folded, for Message::canonical_mime.
+fn write_headers(bytes: &mut Vec<u8>, headers: &Headers, ending: &str, fold: bool) {
+    let lines: Vec<String> = headers.iter()
+        .map(|header| if fold { fold_header(&header.to_string()) } else { header.to_string() })
+        .collect();
+    bytes.extend_from_slice(lines.join(ending).as_bytes());
+    bytes.extend_from_slice(ending.repeat(2).as_bytes());
+}
+
+// Write a delimiter line for Message::to_bytes, with the given suffix (-- to close) and line
+// ending. The delimiter must start a line, so a preceding body without a final line break gets one.
+fn write_delimiter(bytes: &mut Vec<u8>, boundary: &str, suffix: &str, ending: &str) {
+    if !bytes.ends_with(b"\n") {
+        bytes.extend_from_slice(ending.as_bytes());
+    }
+    bytes.extend_from_slice(format!("--{}{}{}", boundary, suffix, ending).as_bytes());
+}
+
+// Fold a header line longer than 78 characters by breaking before whitespace (RFC 5322 section
+// 2.2.3). Lines which are already folded are left as they are, as are words too long to fold.
+fn fold_header(line: &str) -> String {
+    const LIMIT: usize = 78;
+    if line.contains('\n') || line.chars().count() <= LIMIT {
+        return line.to_string();
+    }
+    let mut folded = String::with_capacity(line.len() + line.len() / LIMIT * 2);
+    let mut length = 0;
+    for (index, word) in line.split(' ').enumerate() {
+        let word_length = word.chars().count();
+        if index > 0 {
+            // Never fold between the key and the start of the value
+            if length + 1 + word_length > LIMIT && index > 1 {
+                folded.push('\n');
+                length = 0;
+            }
+            folded.push(' ');
+            length += 1;
+        }
+        folded.push_str(word);
+        length += word_length;
+    }
+    folded
+}
+
+/// A non-fatal problem found by [`Message::validate`].
+///
+/// `crumble` parses permissively, recovering what it can from noncompliant documents. These
+/// warnings report where that recovery was needed, for consumers that want to be strict.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Warning {
+    /// Parameters of the named header are separated by whitespace instead of `;`.
+    UnseparatedParameters(String),
+    /// More than one `Content-Type` header was given for the same part. Only the first is used.
+    DuplicateContentType,
+    /// A body breaks the rules of its declared `7bit` or `8bit` transfer encoding, e.g. a `7bit`
+    /// body with bytes above 127. The encoding is named. See [`decode::decode_7bit`].
+    InvalidTransferEncoding(String),
+}
+
+/// The kind of S/MIME message, as classified by [`Message::smime_type`].
+#[derive(Debug, PartialEq, Clone)]
+pub enum SmimeKind {
+    /// Encrypted content (`smime-type=enveloped-data`).
+    EnvelopedData,
+    /// Encrypted and authenticated content (`smime-type=authEnveloped-data`).
+    AuthEnvelopedData,
+    /// Opaque signed content, with the signed data embedded in the signature (`smime-type=signed-data`).
+    SignedData,
+    /// Compressed content (`smime-type=compressed-data`).
+    CompressedData,
+    /// A certificate management message (`smime-type=certs-only`).
+    CertsOnly,
+    /// A `multipart/signed` message with a detached `application/pkcs7-signature` part.
+    DetachedSignature,
+    /// An `application/pkcs7-mime` part with a missing or unrecognised `smime-type`.
+    Unknown,
+}
+
+/// Message flags stored by mbox tools, as returned by [`Message::mbox_flags`].
+///
+/// Flags are read from the letters of the `Status:` and `X-Status:` headers, and keywords from
+/// `X-Keywords:`.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct MboxFlags {
+    /// `R`: the message has been read.
+    pub read: bool,
+    /// `O`: the message is old, i.e. has been seen by a mail client but not necessarily read.
+    pub old: bool,
+    /// `A`: the message has been answered.
+    pub answered: bool,
+    /// `D`: the message is marked for deletion.
+    pub deleted: bool,
+    /// `F`: the message is flagged.
+    pub flagged: bool,
+    /// User-defined keywords, in order.
+    pub keywords: Vec<String>,
+}
+
+/// A byte range in the document a value was parsed from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Representation of a section of a MIME document.
+///
+/// MIME sections can be some text; a header and some text or data; or nested combinations.
+/// This parser may produce empty sections, for example after the closing delimiter of a multipart
+/// body, or for a part with headers but no body. [`Message::prune_empty`] removes them.
+///
+/// Each section records the [`Span`] of the document it was parsed from: the body of a `Plain`
+/// section, or the whole part (headers included) of a `Multipart` section. Spans are not
+/// considered when comparing sections.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Section {
+    Plain {
+        #[cfg_attr(feature = "serde", serde(with = "serde_base64"))]
+        body: Vec<u8>,
+        span: Span,
+    },
+    Multipart {
+        headers: Headers,
+        body: Vec<Box<Section>>,
+        span: Span,
+    },
+    Empty,
+}
+
+impl PartialEq for Section {
+    fn eq(&self, other: &Section) -> bool {
+        match (self, other) {
+            (Section::Plain {body: a, ..}, Section::Plain {body: b, ..}) => a == b,
+            (Section::Multipart {headers: a_headers, body: a_body, ..}, Section::Multipart {headers: b_headers, body: b_body, ..}) => {
+                a_headers == b_headers && a_body == b_body
+            },
+            (Section::Empty, Section::Empty) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Writes the section as MIME, as it appears between the delimiters of a multipart document. Bytes
+/// which aren't valid UTF-8 are replaced with U+FFFD.
+impl std::fmt::Display for Section {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut bytes = Vec::new();
+        self.write_bytes(&mut bytes, "\n", false);
+        f.write_str(&String::from_utf8_lossy(&bytes))
+    }
+}
+
+impl Section {
+    /// Parse a single MIME part on its own, e.g. one extracted from a document elsewhere.
+    ///
+    /// A part with a `Content-Type` or `Content-Transfer-Encoding` header near its start is read as
+    /// headers and a body, giving a `Section::Multipart`; otherwise it is a `Section::Plain` body.
+    /// Spans are positions in `raw_section`.
+    ///
+    /// ```
+    /// use crumble::Section;
+    /// let part = Section::new("Content-Type: text/plain\nContent-Transfer-Encoding: base64\n\nSGVsbG8=").unwrap();
+    /// assert_eq!(part.content_type(), Some("text/plain"));
+    /// assert_eq!(part.decoded_text().unwrap(), "Hello");
+    ///
+    /// let body = Section::new("Just some text").unwrap();
+    /// assert!(body.is_plain());
+    /// ```
+    pub fn new(raw_section: &str) -> Result<Section, Error> {
+        Section::from_bytes(raw_section.as_bytes())
+    }
+
+    /// Parse a single MIME part which may not be valid UTF-8, as with [`Section::new`].
+    ///
+    /// Invalid UTF-8 in the headers is replaced with U+FFFD. Bodies are kept as the bytes they
+    /// were given.
+    pub fn from_bytes(raw_section: &[u8]) -> Result<Section, Error> {
+        SectionRef::new(raw_section).map(SectionRef::into_owned)
+    }
+
+    /// Decode the body of a plain section according to the `content-transfer-encoding` of the
+    /// enclosing headers.
+    ///
+    /// `base64` and `quoted-printable` bodies are decoded; `7bit`, `8bit`, `binary` and unknown
+    /// encodings are returned as is. The raw body is left untouched. Malformed base64 returns
+    /// `Error::ParseError`. `Empty` sections decode to nothing; multipart sections have no body
+    /// of their own and return `Error::ParseError`.
+    pub fn decoded_body(&self, headers: &Headers) -> Result<Vec<u8>, Error> {
+        match self {
+            Section::Plain {body, ..} => match transfer_encoding(headers).as_deref() {
+                Some("base64") => decode::decode_base64(body),
+                Some("quoted-printable") => decode::decode_quoted_printable(body),
+                // 7bit, 8bit and binary bodies are not encoded, and unknown encodings are passed
+                // through for the consumer to handle. Mislabelled bodies are common, so the rules
+                // of 7bit and 8bit are only checked by Message::validate.
+                _ => Ok(body.clone()),
+            },
+            Section::Multipart {..} => Err(Error::ParseError),
+            Section::Empty => Ok(Vec::new()),
+        }
+    }
+
+    /// The usable value of this section's first header named `key`, ignoring case.
+    ///
+    /// The value is unfolded onto one line and RFC 2047 encoded-words are decoded. A value which
+    /// can't be decoded is returned unfolded but otherwise as given.
+    pub fn header_value(&self, key: &str) -> Option<String> {
+        let value = unfold(self.headers().get(key)?);
+        Some(decode::decode_encoded_words(&value).unwrap_or(value))
+    }
+
+    /// Decode the text of a part: its body is decoded according to the part's own
+    /// `content-transfer-encoding`, then converted from the part's own charset.
+    ///
+    /// Only the part's own headers are consulted, so the text parts of an embedded
+    /// `message/rfc822` decode with their declared charsets rather than the enclosing part's.
+    /// A part without a charset is treated as US-ASCII, as are `Plain` sections, which have no
+    /// headers. Returns `Error::ParseError` for a section containing other parts.
+    ///
+    /// ISO-8859-1 and Windows-1252 are decoded; any other charset is decoded as UTF-8, returning
+    /// `Error::InvalidString` if the text isn't valid UTF-8.
+    pub fn decoded_text(&self) -> Result<String, Error> {
+        let (headers, body) = match self {
+            Section::Multipart {headers, body, ..} => match body.as_slice() {
+                [body] if matches!(**body, Section::Plain {..} | Section::Empty) => (headers, &**body),
+                _ => return Err(Error::ParseError),
+            },
+            section => (Headers::EMPTY, section),
+        };
+        body.decoded_text_in(headers)
+    }
+
+    // Decode the text of this body, which belongs to a part with the given headers.
+    fn decoded_text_in(&self, headers: &Headers) -> Result<String, Error> {
+        let charset = content_type_param(headers, "charset").unwrap_or_else(|| String::from("us-ascii"));
+        decode::decode_charset(&charset, &self.decoded_body(headers)?)
+    }
+
+    /// The `charset` parameter of this section's own `content-type`, lowercased.
+    ///
+    /// Charsets are not inherited: a part nested in another, including the parts of an embedded
+    /// `message/rfc822`, only has the charset it declares itself.
+    pub fn charset(&self) -> Option<String> {
+        content_type_param(self.headers(), "charset").map(|charset| charset.to_lowercase())
+    }
+
+    /// Search the bodies of this section and any sections nested in it for `needle`.
+    ///
+    /// With `decode`, bodies are first decoded according to their part's
+    /// `content-transfer-encoding`, as with [`Section::decoded_body`]. A body which fails to decode
+    /// is searched as is.
+    pub fn body_contains(&self, needle: &[u8], decode: bool) -> bool {
+        match self {
+            Section::Plain {body, ..} => contains(body, needle),
+            Section::Multipart {headers, body, ..} => body.iter().any(|section| section.body_contains_in(headers, needle, decode)),
+            Section::Empty => contains(&[], needle),
+        }
+    }
+
+    // Search this section, which is nested in a part with the given headers.
+    fn body_contains_in(&self, headers: &Headers, needle: &[u8], decode: bool) -> bool {
+        match self {
+            Section::Plain {..} if decode => match self.decoded_body(headers) {
+                Ok(body) => contains(&body, needle),
+                Err(_) => self.body_contains(needle, false),
+            },
+            _ => self.body_contains(needle, decode),
+        }
+    }
+
+    /// Whether this is the `Multipart` variant, i.e. a part with headers.
+    ///
+    /// The content type isn't checked: a `text/plain` part with headers is `Multipart` too, and a
+    /// `multipart/*` part is split into several sections only if its boundary was found.
+    pub fn is_multipart(&self) -> bool {
+        matches!(self, Section::Multipart {..})
+    }
+
+    /// Whether this is a `Plain` section: a body without headers.
+    pub fn is_plain(&self) -> bool {
+        matches!(self, Section::Plain {..})
+    }
+
+    /// The value of this section's `content-type` header, if it has one.
+    ///
+    /// This is the declared type of the section itself: for a `message/rfc822` section it is
+    /// `message/rfc822`, not the type of the embedded message. `Plain` and `Empty` sections have
+    /// no headers, so return `None`.
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers().get("Content-Type")
+    }
+
+    /// All parameters of this section's `content-type`, in order, with lowercased keys.
+    ///
+    /// Repeated parameters are all included. Accessors such as [`Section::charset`] use the last
+    /// occurrence.
+    pub fn content_type_params(&self) -> Vec<(String, String)> {
+        self.content_type().map(parse_parameters).unwrap_or_default()
+    }
+
+    /// The parsed `content-type` header of this section, if it has one which can be parsed.
+    ///
+    /// Only the first `content-type` header counts, as with [`Section::content_type`].
+    pub fn parsed_content_type(&self) -> Option<ContentType> {
+        ContentType::parse(self.content_type()?).ok()
+    }
+
+    /// The parsed `content-disposition` header of this section, if it has one which can be parsed.
+    pub fn content_disposition(&self) -> Option<ContentDisposition> {
+        ContentDisposition::parse(self.headers().get("Content-Disposition")?).ok()
+    }
+
+    // The name of the file this part holds: the disposition's filename (see
+    // ContentDisposition::filename), falling back to the name parameter of its Content-Type.
+    fn filename(&self) -> Option<String> {
+        self.content_disposition().and_then(|disposition| disposition.filename())
+            .or_else(|| self.parsed_content_type()?.decoded_param("name"))
+    }
+
+    /// The number of headers belonging to this section. `Plain` and `Empty` sections have none.
+    pub fn header_count(&self) -> usize {
+        self.headers().len()
+    }
+
+    /// The full text of this section as it appeared in `source`, the document it was parsed from.
+    ///
+    /// For a multipart section this is the entire part between its delimiter lines, headers
+    /// included, byte-exact. For a plain section it is just the body. Returns an empty string for
+    /// `Empty` sections, or if the section was not parsed from `source`.
+    pub fn raw_with_headers<'a>(&self, source: &'a str) -> &'a str {
+        match self {
+            Section::Plain {span, ..} | Section::Multipart {span, ..} => source.get(span.start..span.end).unwrap_or(""),
+            Section::Empty => "",
+        }
+    }
+
+    /// The bytes of this section as they appeared in `source`, as with
+    /// [`Section::raw_with_headers`], for documents which may not be valid UTF-8.
+    ///
+    /// The bytes are exactly those of the input, line endings and transfer encoding included, so
+    /// signatures over them can be verified. A part runs up to the delimiter line which follows it,
+    /// so it ends with the line break before that delimiter. That line break belongs to the
+    /// delimiter ([RFC 2046 section 5.1.1](https://tools.ietf.org/html/rfc2046#section-5.1.1)), so
+    /// it isn't covered by a `multipart/signed` signature and must be removed before hashing.
+    pub fn raw_bytes<'a>(&self, source: &'a [u8]) -> &'a [u8] {
+        match self {
+            Section::Plain {span, ..} | Section::Multipart {span, ..} => source.get(span.start..span.end).unwrap_or_default(),
+            Section::Empty => &[],
+        }
+    }
+
+    // The Content-ID of this part without the surrounding <>.
+    fn content_id(&self) -> Option<&str> {
+        let content_id = self.headers().get("Content-ID")?.trim();
+        Some(content_id.strip_prefix('<').and_then(|id| id.strip_suffix('>')).unwrap_or(content_id))
+    }
+
+    /// The lowercased subtype of a `multipart/*` part, e.g. `mixed`, `alternative` or `related`,
+    /// which says how its parts relate to each other. Returns `None` for any other content type.
+    pub fn multipart_subtype(&self) -> Option<String> {
+        let media_type = media_type(self.headers())?;
+        media_type.strip_prefix("multipart/").map(str::to_string)
+    }
+
+    /// The message embedded in a `message/rfc822` (or `message/global`) part, such as a forwarded
+    /// or bounced email.
+    ///
+    /// Embedded messages are parsed along with the rest of the document, and count towards
+    /// [`ParseOptions::max_depth`]. This returns a copy of the embedded message, whose spans are
+    /// positions in the enclosing document. A multipart embedded message always ends with the
+    /// `Section::Empty` of a closing delimiter, as [`Message::to_bytes`] writes one, even if the
+    /// original was cut off before it. Returns `None` for any other part, or if the embedded
+    /// message has no headers.
+    ///
+    /// ```
+    /// use crumble::Message;
+    /// let message = Message::new("Content-Type: multipart/mixed; boundary=x\n\n--x\nContent-Type: message/rfc822\n\nSubject: Hi\n\nHello\n--x--\n").unwrap();
+    /// let forwarded = message.sections[0].embedded_message().unwrap();
+    /// assert_eq!(forwarded.subject().as_deref(), Some("Hi"));
+    /// ```
+    pub fn embedded_message(&self) -> Option<Message> {
+        if !is_message_type(self.headers()) {
+            return None;
+        }
+        let (headers, body) = match self {
+            Section::Multipart {body, ..} => match body.first().map(|section| &**section) {
+                Some(Section::Multipart {headers, body, ..}) => (headers, body),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        let mut sections: Vec<Section> = body.iter().map(|section| (**section).clone()).collect();
+        // A multipart message parsed on its own has a trailing Empty section for its closing
+        // delimiter. The tree doesn't record whether the delimiter was there, so it is assumed.
+        if headers.get("Content-Type").is_some_and(is_multipart_type) {
+            sections.push(Section::Empty);
+        }
+        Some(Message {
+            headers: headers.clone(),
+            sections,
+            raw_headers: format_headers(headers),
+            preamble: String::new(),
+            source: None,
+            options: ParseOptions::default(),
+        })
+    }
+
+    /// True if this section is an iCalendar (`text/calendar`) part, such as a meeting invitation.
+    pub fn is_calendar(&self) -> bool {
+        media_type(self.headers()).as_deref() == Some("text/calendar")
+    }
+
+    /// The iCalendar method of a `text/calendar` part, e.g. `REQUEST` or `CANCEL`, uppercased.
+    ///
+    /// This is read from the `method` parameter of the content type.
+    pub fn calendar_method(&self) -> Option<String> {
+        if !self.is_calendar() {
+            return None;
+        }
+        content_type_param(self.headers(), "method").map(|method| method.to_uppercase())
+    }
+
+    // Write this section and those nested in it for Message::debug_tree.
+    fn write_tree(&self, tree: &mut String, depth: usize) {
+        let indent = "  ".repeat(depth);
+        match self {
+            Section::Plain {body, ..} => writeln!(tree, "{}Plain ({})", indent, count(body.len(), "byte")),
+            Section::Multipart {headers, ..} => writeln!(tree, "{}Multipart {} ({})", indent,
+                media_type(headers).unwrap_or_else(|| String::from("-")), count(headers.len(), "header")),
+            Section::Empty => writeln!(tree, "{}Empty", indent),
+        }.expect("Error constructing string.");
+        if let Section::Multipart {body, ..} = self {
+            for section in body {
+                section.write_tree(tree, depth + 1);
+            }
+        }
+    }
+
+    // Remove Empty sections nested in this one, for Message::prune_empty.
+    fn prune_empty(&mut self) {
+        if let Section::Multipart {body, ..} = self {
+            body.retain(|section| **section != Section::Empty);
+            for section in body {
+                section.prune_empty();
+            }
+        }
+    }
+
+    // Serialise this section for Message::to_bytes, ending the lines it adds with `ending` and
+    // folding long header lines if `fold` is set.
+    fn write_bytes(&self, bytes: &mut Vec<u8>, ending: &str, fold: bool) {
+        match self {
+            Section::Plain {body, ..} => bytes.extend_from_slice(body),
+            Section::Multipart {headers, body, ..} => {
+                write_headers(bytes, headers, ending, fold);
+                match headers.get("Content-Type").and_then(boundary_param) {
+                    Some(boundary) => {
+                        // Nested parts have no Empty section for the closing delimiter
+                        for section in body {
+                            write_delimiter(bytes, &boundary, "", ending);
+                            section.write_bytes(bytes, ending, fold);
+                        }
+                        write_delimiter(bytes, &boundary, "--", ending);
+                    },
+                    None => for section in body {
+                        section.write_bytes(bytes, ending, fold);
+                    },
+                }
+            },
+            Section::Empty => (),
+        }
+    }
+
+    // Compare ignoring cosmetic differences, for Message::eq_structural.
+    fn eq_structural(&self, other: &Section) -> bool {
+        match (self, other) {
+            (Section::Plain {body: a, ..}, Section::Plain {body: b, ..}) => normalize_body(a) == normalize_body(b),
+            (Section::Multipart {headers: a_headers, body: a_body, ..}, Section::Multipart {headers: b_headers, body: b_body, ..}) => {
+                headers_eq_structural(a_headers, b_headers)
+                    && a_body.len() == b_body.len()
+                    && a_body.iter().zip(b_body).all(|(a, b)| a.eq_structural(b))
+            },
+            (Section::Empty, Section::Empty) => true,
+            _ => false,
+        }
+    }
+
+    // Headers belonging to this section. Only multipart sections have headers.
+    fn headers(&self) -> &Headers {
+        match self {
+            Section::Multipart {headers, ..} => headers,
+            _ => Headers::EMPTY,
+        }
+    }
+
+    // Visit this section and every section nested in it, in depth-first order.
+    fn walk(&self, f: &mut impl FnMut(&Section)) {
+        f(self);
+        if let Section::Multipart {body, ..} = self {
+            for section in body {
+                section.walk(f);
+            }
+        }
+    }
+
+    /// Drive a [`Visitor`] over this section and the sections nested in it.
+    pub fn visit(&self, visitor: &mut impl Visitor) {
+        self.visit_at(visitor, 0);
+    }
+
+    fn visit_at(&self, visitor: &mut impl Visitor, depth: usize) {
+        match self {
+            Section::Plain {body, ..} => visitor.visit_plain(body, depth),
+            Section::Multipart {headers, body, ..} => {
+                visitor.visit_multipart(headers, depth);
+                for section in body {
+                    section.visit_at(visitor, depth + 1);
+                }
+                visitor.leave_multipart(headers, depth);
+            },
+            Section::Empty => visitor.visit_empty(depth),
+        }
+    }
+
+    fn walk_headers(&self, f: &mut impl FnMut(&Header)) {
+        if let Section::Multipart {headers, body, ..} = self {
+            for header in headers {
+                f(header);
+            }
+            for section in body {
+                section.walk_headers(f);
+            }
+        }
+    }
+
+    fn walk_headers_mut(&mut self, f: &mut impl FnMut(&mut Header)) {
+        if let Section::Multipart {headers, body, ..} = self {
+            for header in headers {
+                f(header);
+            }
+            for section in body {
+                section.walk_headers_mut(f);
+            }
+        }
+    }
+
+    // Check that a plain body keeps to the rules of the transfer encoding in the given headers.
+    fn validate_transfer_encoding(&self, headers: &Headers, warnings: &mut Vec<Warning>) {
+        if let Section::Plain {body, ..} = self {
+            let encoding = transfer_encoding(headers);
+            let valid = match encoding.as_deref() {
+                Some("7bit") => decode::decode_7bit(body).is_ok(),
+                Some("8bit") => decode::decode_8bit(body).is_ok(),
+                _ => true,
+            };
+            if !valid {
+                warnings.push(Warning::InvalidTransferEncoding(encoding.unwrap_or_default()));
+            }
+        }
+    }
+
+    fn validate(&self, warnings: &mut Vec<Warning>) {
+        if let Section::Multipart {headers, body, ..} = self {
+            validate_headers(headers, warnings);
+            if let [body] = body.as_slice() {
+                body.validate_transfer_encoding(headers, warnings);
+            }
+            for section in body {
+                section.validate(warnings);
+            }
+        }
+    }
+}
+
+/// A section which borrows its bodies from the document it was parsed from.
+///
+/// This is the same tree as [`Section`], but `Plain` bodies are slices of the document rather than
+/// copies, and nested sections are held directly rather than boxed. Parsing with
+/// [`MessageRef`] or [`SectionRef::new`] avoids an allocation per body and per part, which adds
+/// up when processing many documents. [`SectionRef::into_owned`] converts to a `Section`.
+///
+/// Spans are not considered when comparing sections.
+#[derive(Debug, Clone)]
+pub enum SectionRef<'a> {
+    Plain {
+        body: &'a [u8],
+        span: Span,
+    },
+    Multipart {
+        headers: Headers,
+        body: Vec<SectionRef<'a>>,
+        span: Span,
+    },
+    Empty,
+}
+
+impl PartialEq for SectionRef<'_> {
+    fn eq(&self, other: &SectionRef) -> bool {
+        match (self, other) {
+            (SectionRef::Plain {body: a, ..}, SectionRef::Plain {body: b, ..}) => a == b,
+            (SectionRef::Multipart {headers: a_headers, body: a_body, ..}, SectionRef::Multipart {headers: b_headers, body: b_body, ..}) => {
+                a_headers == b_headers && a_body == b_body
+            },
+            (SectionRef::Empty, SectionRef::Empty) => true,
+            _ => false,
+        }
+    }
+}
+
+impl From<SectionRef<'_>> for Section {
+    fn from(section: SectionRef) -> Section {
+        section.into_owned()
+    }
+}
+
+impl<'a> SectionRef<'a> {
+    /// Parse a section, as with [`Section::new`], borrowing its bodies from `raw_section`.
+    pub fn new(raw_section: &'a [u8]) -> Result<SectionRef<'a>, Error> {
+        SectionRef::with_options(raw_section, &ParseOptions::default())
+    }
+
+    // Parse a section on its own using the given options, with spans relative to `raw_section`.
+    fn with_options(raw_section: &'a [u8], options: &ParseOptions) -> Result<SectionRef<'a>, Error> {
+        let context = Context {
+            source: raw_section,
+            options,
+            depth: 0,
+        };
+        SectionRef::parse(raw_section, &context)
+    }
+
+    /// Copy the bodies into an owned [`Section`].
+    pub fn into_owned(self) -> Section {
+        match self {
+            SectionRef::Plain {body, span} => Section::Plain {
+                body: body.to_vec(),
+                span,
+            },
+            SectionRef::Multipart {headers, body, span} => Section::Multipart {
+                headers,
+                body: body.into_iter().map(|section| Box::new(section.into_owned())).collect(),
+                span,
+            },
+            SectionRef::Empty => Section::Empty,
+        }
+    }
+
+    fn parse(raw_section: &'a [u8], context: &Context) -> Result<SectionRef<'a>, Error> {
+        // A section can either be just some plain text, or be split into headers/body.
+        // That body is also section.
+        // If the raw section has no headers, return it as plain
+        // If it has headers, split off the headers and recurse
+
+        // A part with nothing but whitespace between its delimiters is empty, as is the -- left
+        // over from a closing delimiter. Splitting never passes anything after a closing delimiter
+        // here (see split_boundary), so other content is kept even if it looks like a delimiter.
+        if raw_section.trim_ascii().is_empty() || SectionRef::is_closing_delimiter(raw_section) {
+            return Ok(SectionRef::Empty);
+        }
+
+        // A section with headers has a different parsing pipeline than one without.
+        if SectionRef::has_headers(raw_section, context.options.header_scan_limit) {
+            SectionRef::parse_multipart(raw_section, context)
+        } else {
+            Ok(SectionRef::Plain {
+                body: raw_section,
+                span: context.span(raw_section),
+            })
+        }
+    }
+
+    fn has_headers(raw_message: &[u8], scan_limit: usize) -> bool {
+        // If there are headers there should be a content-type, or at least a transfer encoding:
+        // a part without a content-type is text/plain, but may still be encoded
+        // Note that headers may be separated by a boundary (nested sections) or newlines (not
+        // nested)
+        // Performance: Assume that the header is not too long and the boundary appears early.
+        // This runs for every part, so lines are scanned by hand rather than with a regex.
+        let raw = &raw_message[..raw_message.len().min(scan_limit)];
+        raw.split(|&b| b == b'\n').any(|line| {
+            let colon = match header_key_colon(line) {
+                Some(colon) => colon,
+                None => return false,
+            };
+            let key = line[..colon].trim_ascii_end();
+            let mut value = line[colon + 1..].iter().skip_while(|&&b| b == b' ' || b == b'\t');
+            (key.eq_ignore_ascii_case(b"Content-Type") || key.eq_ignore_ascii_case(b"Content-Transfer-Encoding"))
+                && value.next().is_some_and(|b| !b.is_ascii_whitespace())
+        })
+    }
+
+    // Whether a part is the -- ending a closing delimiter, as passed to Section::new.
+    fn is_closing_delimiter(raw_section: &[u8]) -> bool {
+        matches!(raw_section, b"--" | b"--\n" | b"--\r\n")
+    }
+
+    fn parse_multipart(raw_section: &'a [u8], context: &Context) -> Result<SectionRef<'a>, Error> {
+        // The body can contain a series of (possibly nested) sections
+        // So check for a boundary.
+        // If there is a boundary, split the body and iterate.
+        // Otherwise, just return a single-entry Vec
+
+        let context = &context.nested()?;
+
+        // Only the section's own headers can declare its boundary. Anything after them is content,
+        // which may be an embedded message with boundaries of its own (e.g. message/rfc822).
+        // The headers are parsed once up front and reused, as this runs at every level of nesting.
+        let (raw_headers, body) = split_blank_line(raw_section);
+        let headers = context.headers(raw_headers)?;
+        // A missing or empty boundary (e.g. boundary="") can't separate anything, so the body is
+        // a single section rather than being split on every --
+        if let Some(boundary) = headers.get("Content-Type").and_then(boundary_param) {
+            // Each section is separated by --<boundary>, and finishes with --<boundary>--
+            let boundary = format!("--{}", boundary);
+            // Any epilogue after the closing delimiter is dropped. If the closing delimiter is
+            // missing, the last part runs to the end of the section.
+            let raw_sections = split_boundary(raw_section, boundary.as_bytes()).0;
+
+            // Any preamble after the part's headers is ignored. Without a blank line after the
+            // headers, the block parsed above ran on into the first part, so it is parsed again.
+            let part_headers = split_blank_line(raw_sections[0]).0;
+            let headers = if part_headers.len() == raw_headers.len() {
+                headers
+            } else {
+                context.headers(part_headers)?
+            };
+
+            let mut sections = Vec::new();
+            for section in &raw_sections[1..] {
+                // Recursively construct sections
+                sections.push(SectionRef::parse(section, context)?);
+            }
+
+            Ok(SectionRef::Multipart {
+                headers,
+                body: sections,
+                span: context.span(raw_section),
+            })
+        } else {
+            // Process body. A part which ends after its headers has an empty body. An embedded
+            // message always starts with headers, even if none of them describe its content.
+            let body = match body {
+                Some(body) if is_message_type(&headers) && header_key_colon(body).is_some() => SectionRef::parse_multipart(body, context)?,
+                Some(body) => SectionRef::parse(body, context)?,
+                None => SectionRef::Empty,
+            };
+            let sections = vec![body];
+
+            Ok(SectionRef::Multipart {
+                headers,
+                body: sections,
+                span: context.span(raw_section),
+            })
+        }
+    }
+}
+
+/// Hooks called for each section of a tree by [`Section::visit`] and [`Message::visit`].
+///
+/// Sections are visited in depth-first order. `depth` is 0 for the top-level sections of a
+/// message, or for the section `visit` was called on, and one more for each enclosing multipart
+/// section. Every hook does nothing by default, so a visitor only implements those it needs.
+///
+/// ```
+/// use crumble::{Headers, Message, Visitor};
+///
+/// struct Outline(Vec<String>);
+///
+/// impl Visitor for Outline {
+///     fn visit_plain(&mut self, body: &[u8], depth: usize) {
+///         self.0.push(format!("{}{} bytes", "  ".repeat(depth), body.len()));
+///     }
+///
+///     fn visit_multipart(&mut self, headers: &Headers, depth: usize) {
+///         self.0.push(format!("{}{} headers", "  ".repeat(depth), headers.len()));
+///     }
+/// }
+///
+/// let message = Message::new("Content-Type: multipart/mixed; boundary=x\n\n--x\nContent-Type: text/plain\n\nHello\n--x--").unwrap();
+/// let mut outline = Outline(Vec::new());
+/// message.visit(&mut outline);
+/// assert_eq!(outline.0, vec!["1 headers", "  6 bytes"]);
+/// ```
+pub trait Visitor {
+    /// Called for a plain body.
+    fn visit_plain(&mut self, _body: &[u8], _depth: usize) {}
+
+    /// Called for a multipart section, before its nested sections are visited.
+    fn visit_multipart(&mut self, _headers: &Headers, _depth: usize) {}
+
+    /// Called for a multipart section, after its nested sections have been visited.
+    fn leave_multipart(&mut self, _headers: &Headers, _depth: usize) {}
+
+    /// Called for an empty section.
+    fn visit_empty(&mut self, _depth: usize) {}
+}
+
+// Depth-first iterator for Message::walk.
+struct Sections<'a> {
+    stack: Vec<&'a Section>,
+}
+
+impl<'a> Iterator for Sections<'a> {
+    type Item = &'a Section;
+
+    fn next(&mut self) -> Option<&'a Section> {
+        let section = self.stack.pop()?;
+        if let Section::Multipart {body, ..} = section {
+            self.stack.extend(body.iter().rev().map(|section| &**section));
+        }
+        Some(section)
+    }
+}
+
+// Depth-first iterator for Message::iter_sections_mut.
+struct SectionsMut<'a> {
+    stack: Vec<&'a mut Section>,
+}
+
+impl<'a> Iterator for SectionsMut<'a> {
+    type Item = &'a mut Section;
+
+    fn next(&mut self) -> Option<&'a mut Section> {
+        loop {
+            let section = self.stack.pop()?;
+            let container = match section {
+                Section::Multipart {body, ..} => body.iter().any(|section| matches!(**section, Section::Multipart {..})),
+                _ => false,
+            };
+            if !container {
+                return Some(section);
+            }
+            if let Section::Multipart {body, ..} = section {
+                self.stack.extend(body.iter_mut().rev().map(|section| &mut **section));
+            }
+        }
+    }
+}
+
+/// Representation of a MIME document.
+///
+/// MIME documents have a large initial key-value header, followed by one or more text/data sections.
+/// A section can be some plain text; a header with text or data; or some nested combination.
+///
+/// Messages are compared by their headers and sections. How they were parsed, e.g. whether the
+/// source document was retained, is not considered.
+///
+/// With the `serde` feature, messages can be serialised and deserialised. Plain bodies are
+/// serialised as base64 strings. A retained source document is not serialised.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Message {
+    pub headers: Headers,
+    pub sections: Vec<Section>,
+    raw_headers: String,
+    preamble: String,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    source: Option<Vec<u8>>,
+    // The options the retained source was parsed with
+    #[cfg_attr(feature = "serde", serde(skip))]
+    options: ParseOptions,
+}
+
+impl PartialEq for Message {
+    fn eq(&self, other: &Message) -> bool {
+        self.headers == other.headers && self.sections == other.sections
+    }
+}
+
+/// Writes the document as MIME, as with [`Message::to_bytes`], so the output parses back to an
+/// equal message. Bytes which aren't valid UTF-8 are replaced with U+FFFD. See
+/// [`Message::debug_tree`] for an outline of the structure instead.
+impl std::fmt::Display for Message {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&String::from_utf8_lossy(&self.to_bytes()))
+    }
+}
+
+/// Parse a MIME document, as with [`Message::new`].
+impl std::convert::TryFrom<&str> for Message {
+    type Error = Error;
+
+    fn try_from(raw_message: &str) -> Result<Message, Error> {
+        Message::from_bytes(raw_message.as_bytes())
+    }
+}
+
+/// Parse a MIME document, as with [`Message::from_bytes`].
+impl std::convert::TryFrom<&[u8]> for Message {
+    type Error = Error;
+
+    fn try_from(raw_message: &[u8]) -> Result<Message, Error> {
+        Message::from_bytes(raw_message)
+    }
+}
+
+impl Message {
+    /// Parse a MIME document and return structured representation.
+    /// Performance should be reasonable: provided tests take between 5 and 200μs per document.
+    ///
+    /// Truncated multipart documents are handled as follows:
+    /// - if the boundary never appears, the body can't be split and is parsed as a plain message;
+    /// - if the closing delimiter is missing, the last part runs to the end of the document;
+    /// - otherwise the closing delimiter becomes a trailing `Section::Empty`, and any epilogue
+    ///   after it is dropped.
+    ///
+    /// A part with nothing but whitespace between its delimiters is a `Section::Empty`.
+    pub fn new(raw_message: &str) -> Result<Message, Error> {
+        Message::with_options(raw_message, &ParseOptions::default())
+    }
+
+    /// Parse a MIME document as with [`Message::new`], but never fail: a document which can't be
+    /// parsed becomes a message with no headers and a single `Section::Plain` holding the whole
+    /// input, or a single `Section::Empty` if the input is blank.
+    ///
+    /// This suits batch processing, where a malformed document shouldn't stop the rest. Use
+    /// [`Message::new`] to find out why a document couldn't be parsed.
+    ///
+    /// ```
+    /// use crumble::{Message, Section};
+    /// let message = Message::new_lenient("Not a MIME document");
+    /// assert!(message.headers.is_empty());
+    /// assert_eq!(message.sections.len(), 1);
+    /// assert!(matches!(&message.sections[0], Section::Plain {body, ..} if body == b"Not a MIME document"));
+    /// ```
+    pub fn new_lenient(raw_message: &str) -> Message {
+        Message::new(raw_message).unwrap_or_else(|_| {
+            let section = if raw_message.trim().is_empty() {
+                Section::Empty
+            } else {
+                Section::Plain {
+                    body: raw_message.as_bytes().to_vec(),
+                    span: Span {start: 0, end: raw_message.len()},
+                }
+            };
+            Message {
+                headers: Headers::new(),
+                sections: vec![section],
+                raw_headers: String::new(),
+                preamble: String::new(),
+                source: None,
+            options: ParseOptions::default(),
+            }
+        })
+    }
+
+    /// Parse a MIME document which may not be valid UTF-8, e.g. one with a Latin-1 body.
+    ///
+    /// Only the headers need to be text, and they are ASCII in practice: any invalid UTF-8 in
+    /// them is replaced with U+FFFD. Bodies are kept as the bytes they were given.
+    pub fn from_bytes(raw_message: &[u8]) -> Result<Message, Error> {
+        Message::parse_bytes(raw_message, &ParseOptions::default())
+    }
+
+    /// Read a MIME document from a reader and parse it, as with [`Message::from_bytes`].
+    ///
+    /// Failures to read the document are returned as `Error::Io`, distinct from failures to parse it.
+    /// Use [`Parser::parse_reader`] to stop reading at a [`ParseOptions::max_len`].
+    pub fn from_reader<R: std::io::Read>(reader: R) -> Result<Message, Error> {
+        Message::read(reader, &ParseOptions::default())
+    }
+
+    fn read<R: std::io::Read>(mut reader: R, options: &ParseOptions) -> Result<Message, Error> {
+        use std::io::Read;
+        let mut raw_message = Vec::new();
+        match options.max_len {
+            // Read one byte past the limit, so a longer document is rejected by parse_bytes
+            // without the rest of it being read
+            Some(max_len) => reader.take((max_len as u64).saturating_add(1)).read_to_end(&mut raw_message)?,
+            None => reader.read_to_end(&mut raw_message)?,
+        };
+        Message::parse_bytes(&raw_message, options)
+    }
+
+    /// Parse a MIME document, as with [`Message::new`], using the given options.
+    pub fn with_options(raw_message: &str, options: &ParseOptions) -> Result<Message, Error> {
+        Message::parse_bytes(raw_message.as_bytes(), options)
+    }
+
+    fn parse_bytes(raw_message: &[u8], options: &ParseOptions) -> Result<Message, Error> {
+        let mut message = MessageRef::with_options(raw_message, options)?.into_owned();
+        if options.retain_source {
+            message.source = Some(raw_message.to_vec());
+            message.options = options.clone();
+        }
+        Ok(message)
+    }
+
+    /// The document this message was parsed from, if it was retained with
+    /// [`ParseOptions::retain_source`] and is valid UTF-8.
+    ///
+    /// Section spans are positions in this document.
+    pub fn source(&self) -> Option<&str> {
+        std::str::from_utf8(self.source.as_deref()?).ok()
+    }
+
+    /// The document this message was parsed from, if it was retained with
+    /// [`ParseOptions::retain_source`].
+    pub fn source_bytes(&self) -> Option<&[u8]> {
+        self.source.as_deref()
+    }
+
+    /// Serialise the document for sending, with CRLF line endings throughout.
+    ///
+    /// Unlike [`Message::to_bytes`], which writes the line ending the document was parsed with,
+    /// every line ends with `\r\n`, and header lines longer than 78 characters are folded at
+    /// whitespace where possible. Bodies are written in their existing transfer encoding. The
+    /// output can be passed directly to an SMTP client's `DATA` command, which is responsible for
+    /// dot-stuffing.
+    pub fn canonical_mime(&self) -> String {
+        // The original text of unchanged sections isn't reused, so that every header is folded
+        let mime = self.serialise("\r\n", true);
+        let mut canonical = Vec::with_capacity(mime.len() + mime.len() / 32);
+        let mut bytes = mime.iter().peekable();
+        while let Some(&b) = bytes.next() {
+            match b {
+                b'\r' => {
+                    bytes.next_if_eq(&&b'\n');
+                    canonical.extend_from_slice(b"\r\n");
+                },
+                b'\n' => canonical.extend_from_slice(b"\r\n"),
+                b => canonical.push(b),
+            }
+        }
+        if !canonical.ends_with(b"\r\n") {
+            canonical.extend_from_slice(b"\r\n");
+        }
+        String::from_utf8_lossy(&canonical).into_owned()
+    }
+
+    /// Serialise the document as bytes which parse back to an equal message.
+    ///
+    /// Nothing is added around the sections: each part is written
+    /// between its delimiters just as the parser reads it back, and bodies are written as the
+    /// bytes they hold, whatever their charset. Sections which are unchanged since parsing with
+    /// [`ParseOptions::retain_source`] are copied from the source. A body which doesn't end with a
+    /// line break gains one before the following delimiter.
+    ///
+    /// Bodies and header values keep their line breaks as they are. The lines written around them
+    /// (between headers, after a header block, and delimiters) end with `\r\n` if the document
+    /// they were parsed from did, and with `\n` otherwise, so a CRLF document isn't given a mix of
+    /// line endings.
+    ///
+    /// To tell whether a section is unchanged, its text in the source is parsed again, with the
+    /// options the document was parsed with, and compared with it, so with the source retained this
+    /// costs about as much as parsing the document again. Without it, sections are only written out.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.serialise(self.line_ending(), false)
+    }
+
+    // Serialise the document for to_bytes, or with every header folded for canonical_mime. Long
+    // header lines are folded if `canonical` is set, and the source is never reused then.
+    fn serialise(&self, ending: &str, canonical: bool) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_headers(&mut bytes, &self.headers, ending, canonical);
+        bytes.extend_from_slice(self.preamble.as_bytes());
+        let content_type = self.headers.get("Content-Type").unwrap_or_default();
+        match self.headers.boundary() {
+            Some(boundary) if content_type.to_lowercase().starts_with("multipart") => {
+                // The closing delimiter is written after the parts, and parses back to the
+                // trailing Empty section. Any other Empty section is an empty part.
+                let parts = match self.sections.split_last() {
+                    Some((Section::Empty, parts)) => parts,
+                    _ => &self.sections[..],
+                };
+                for section in parts {
+                    write_delimiter(&mut bytes, &boundary, "", ending);
+                    match self.original(section).filter(|_| !canonical) {
+                        Some(original) => bytes.extend_from_slice(original),
+                        None => section.write_bytes(&mut bytes, ending, canonical),
+                    }
+                }
+                write_delimiter(&mut bytes, &boundary, "--", ending);
+            },
+            _ => for section in &self.sections {
+                section.write_bytes(&mut bytes, ending, canonical);
+            },
+        }
+        bytes
+    }
+
+    // The line ending of the document this message was parsed from, judged by its first line
+    // break: in the retained source if there is one, or else the top-level headers or the bodies.
+    fn line_ending(&self) -> &'static str {
+        let bodies = self.walk().filter_map(|section| match section {
+            Section::Plain {body, ..} => Some(body.as_slice()),
+            _ => None,
+        });
+        let text = self.source.as_deref().into_iter().chain(Some(self.raw_headers.as_bytes())).chain(bodies)
+            .find(|text| text.contains(&b'\n'))
+            .unwrap_or_default();
+        match text.iter().position(|&b| b == b'\n') {
+            Some(newline) if newline > 0 && text[newline - 1] == b'\r' => "\r\n",
+            _ => "\n",
+        }
+    }
+
+    /// An indented outline of the document's structure, for debugging.
+    ///
+    /// Each line is a section, indented by its depth, with its content type and number of headers
+    /// or body size. The format is for people to read and may change; use `Display` or
+    /// [`Message::to_bytes`] to serialise the document.
+    ///
+    /// ```
+    /// use crumble::Message;
+    /// let message = Message::new("Content-Type: multipart/mixed; boundary=x\n\n--x\nContent-Type: text/plain\n\nHello\n--x--\n").unwrap();
+    /// assert_eq!(message.debug_tree(), "Message multipart/mixed (1 header)\n  Multipart text/plain (1 header)\n    Plain (6 bytes)\n  Empty\n");
+    /// ```
+    pub fn debug_tree(&self) -> String {
+        let mut tree = String::new();
+        writeln!(tree, "Message {} ({})", media_type(&self.headers).unwrap_or_else(|| String::from("-")), count(self.headers.len(), "header"))
+            .expect("Error constructing string.");
+        for section in &self.sections {
+            section.write_tree(&mut tree, 1);
+        }
+        tree
+    }
+
+    // The original text of a top-level section, if the source was retained and the section has
+    // not been modified since parsing. Sections are plain data which can be changed in place, so
+    // changes aren't tracked: the original text is parsed again and compared with the section.
+    fn original(&self, section: &Section) -> Option<&[u8]> {
+        let original = self.section_bytes(section)?;
+        let reparsed = SectionRef::with_options(original, &self.options).ok()?.into_owned();
+        (reparsed == *section).then_some(original)
+    }
+
+    /// The bytes a section of this message was parsed from, as with [`Section::raw_bytes`], if the
+    /// source document was retained with [`ParseOptions::retain_source`].
+    ///
+    /// The section may be nested at any depth. Its bytes are returned even if it has been modified
+    /// since parsing, so they can be checked against a signature made before any changes. No
+    /// parsing is done, unlike the check [`Message::to_bytes`] makes before reusing them.
+    ///
+    /// ```
+    /// use crumble::{Message, ParseOptions};
+    /// let raw = "Content-Type: multipart/signed; boundary=x\r\n\r\n--x\r\nContent-Type: text/plain\r\n\r\nSigned\r\n--x--\r\n";
+    /// let options = ParseOptions { retain_source: true, ..ParseOptions::default() };
+    /// let message = Message::with_options(raw, &options).unwrap();
+    /// let signed = message.section_bytes(&message.sections[0]).unwrap();
+    /// assert_eq!(signed, b"Content-Type: text/plain\r\n\r\nSigned\r\n");
+    /// ```
+    pub fn section_bytes(&self, section: &Section) -> Option<&[u8]> {
+        Some(section.raw_bytes(self.source.as_deref()?))
+    }
+
+    /// The top-level header block exactly as it appeared in the document.
+    ///
+    /// This excludes the blank line separating the headers from the body, and any multipart preamble.
+    pub fn raw_headers(&self) -> &str {
+        &self.raw_headers
+    }
+
+    /// The text between the top-level headers and the first delimiter of a multipart message,
+    /// e.g. "This is a multi-part message in MIME format." Empty if there is none.
+    ///
+    /// Mail readers don't display the preamble, but it is kept so the message can be written out
+    /// unchanged.
+    pub fn preamble(&self) -> &str {
+        &self.preamble
+    }
+
+    /// The length in bytes of the top-level header block, as returned by [`Message::raw_headers`].
+    ///
+    /// Useful for enforcing header size limits.
+    pub fn header_bytes_len(&self) -> usize {
+        self.raw_headers.len()
+    }
+
+    /// The value of the first top-level header named `key`, ignoring case.
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers.get(key)
+    }
+
+    /// The `Subject` header, unfolded, with RFC 2047 encoded-words decoded.
+    ///
+    /// A subject which can't be decoded is returned as given.
+    pub fn subject(&self) -> Option<String> {
+        let subject = unfold(self.header("subject")?);
+        Some(decode::decode_encoded_words(&subject).unwrap_or(subject))
+    }
+
+    /// The `From` header, unfolded.
+    ///
+    /// Not named `from`, which would hide `Message::from` for converting a [`MessageRef`].
+    pub fn from_header(&self) -> Option<String> {
+        self.header("from").map(unfold)
+    }
+
+    /// The `To` header, unfolded.
+    pub fn to(&self) -> Option<String> {
+        self.header("to").map(unfold)
+    }
+
+    /// The `Date` header, unfolded.
+    pub fn date(&self) -> Option<String> {
+        self.header("date").map(unfold)
+    }
+
+    /// The `Message-ID` header, unfolded.
+    pub fn message_id(&self) -> Option<String> {
+        self.header("message-id").map(unfold)
+    }
+
+    /// The parsed top-level `content-type` header.
+    ///
+    /// Returns `None` if the message declares no content type or it can't be parsed. Only the
+    /// first `content-type` header counts.
+    pub fn content_type(&self) -> Option<ContentType> {
+        ContentType::parse(self.header("content-type")?).ok()
+    }
+
+    /// The values of all top-level headers named `key`, ignoring case, in order.
+    ///
+    /// Useful for headers which are repeated, such as `Received`.
+    pub fn headers_all(&self, key: &str) -> Vec<&str> {
+        self.headers.iter()
+            .filter(|header| header.key.eq_ignore_ascii_case(key))
+            .map(|header| header.value.as_str())
+            .collect()
+    }
+
+    /// The top-level headers grouped by lowercased key.
+    ///
+    /// The values of a repeated header, such as `Received`, are kept in the order they appear.
+    pub fn header_map(&self) -> HashMap<String, Vec<String>> {
+        let mut map: HashMap<String, Vec<String>> = HashMap::new();
+        for header in &self.headers {
+            map.entry(header.key.to_lowercase()).or_default().push(header.value.clone());
+        }
+        map
+    }
+
+    /// The top-level headers as `(key, value)` pairs, in order.
+    ///
+    /// ```
+    /// use std::collections::HashMap;
+    /// use crumble::Message;
+    /// let message = Message::new("Subject: Hello\nTo: example@example.com\n\nHello, world!").unwrap();
+    /// let headers: HashMap<&str, &str> = message.header_entries().collect();
+    /// assert_eq!(headers["Subject"], "Hello");
+    /// ```
+    pub fn header_entries(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers.iter().map(|header| (header.key.as_str(), header.value.as_str()))
+    }
+
+    /// The number of top-level headers. Headers of nested sections are not counted.
+    pub fn header_count(&self) -> usize {
+        self.headers.len()
+    }
+
+    /// Visit every header in the document: the top-level headers, then each section's headers
+    /// in depth-first order.
+    pub fn walk_headers(&self, mut f: impl FnMut(&Header)) {
+        for header in &self.headers {
+            f(header);
+        }
+        for section in &self.sections {
+            section.walk_headers(&mut f);
+        }
+    }
+
+    /// Visit every header in the document mutably, in the same order as [`Message::walk_headers`].
+    ///
+    /// Useful for auditing or redacting headers at all levels of nesting.
+    pub fn walk_headers_mut(&mut self, mut f: impl FnMut(&mut Header)) {
+        for header in &mut self.headers {
+            f(header);
+        }
+        for section in &mut self.sections {
+            section.walk_headers_mut(&mut f);
+        }
+    }
+
+    /// Replace non-ASCII text in header values with RFC 2047 encoded-words, for sending.
+    ///
+    /// Headers of all sections are re-encoded, not just the top-level headers. Only the
+    /// non-ASCII words of a value are encoded, so addresses and other ASCII text are left
+    /// untouched, as are values which are already ASCII. This is the inverse of
+    /// [`Header::decoded_value`].
+    pub fn reencode_headers_ascii(&mut self) {
+        self.walk_headers_mut(|header| {
+            if !header.value.is_ascii() {
+                header.value = encode::encode_non_ascii(&header.value);
+            }
+        });
+    }
+
+    /// Search every body in the document for `needle`, optionally decoding them first.
+    ///
+    /// See [`Section::body_contains`].
+    pub fn body_contains(&self, needle: &[u8], decode: bool) -> bool {
+        self.sections.iter().any(|section| section.body_contains_in(&self.headers, needle, decode))
+    }
+
+    /// Compare two documents, ignoring cosmetic differences.
+    ///
+    /// Headers are compared as a multiset: order and key case don't matter, and values are compared
+    /// with each run of whitespace, including folding, collapsed to a single space. Bodies are compared with
+    /// normalised line endings and trailing whitespace removed from each line and from the end of
+    /// the body. This catches the same message re-wrapped or re-saved by a different tool; the
+    /// structure of the documents must still match.
+    pub fn eq_structural(&self, other: &Message) -> bool {
+        headers_eq_structural(&self.headers, &other.headers)
+            && self.sections.len() == other.sections.len()
+            && self.sections.iter().zip(&other.sections).all(|(a, b)| a.eq_structural(b))
+    }
+
+    /// All readable text in the document, for indexing and search.
+    ///
+    /// Every `text/plain` part is decoded and converted from its charset, and the trimmed texts are
+    /// joined with blank lines, in document order. `text/html` parts are included with their tags
+    /// stripped, except in a `multipart/alternative` which also has a `text/plain` version, so the
+    /// same text isn't included twice. Tag stripping is a simple approximation: `<script>` and
+    /// `<style>` contents and all tags are removed and a few common entities are unescaped, but
+    /// no other HTML structure is interpreted. Parts which fail to decode are skipped.
+    pub fn text_content(&self) -> String {
+        let mut texts = Vec::new();
+        collect_text(&self.headers, self.sections.iter().collect(), &mut texts);
+        texts.join("\n\n")
+    }
+
+    /// The images referenced by `Content-ID`, for resolving `cid:` URLs when rendering HTML.
+    ///
+    /// Every part with a `Content-ID` and an `image/*` content type is included, keyed by its
+    /// content ID without the surrounding `<>`. The values are the lowercased media type and the
+    /// decoded image. Parts which fail to decode are skipped.
+    pub fn inline_images(&self) -> HashMap<String, (String, Vec<u8>)> {
+        let mut images = HashMap::new();
+        for section in &self.sections {
+            section.walk(&mut |section| {
+                let (headers, body) = match section {
+                    Section::Multipart {headers, body, ..} if body.len() == 1 => (headers, &body[0]),
+                    _ => return,
+                };
+                let media_type = match media_type(headers) {
+                    Some(media_type) if media_type.starts_with("image/") => media_type,
+                    _ => return,
+                };
+                let content_id = match section.content_id() {
+                    Some(content_id) => content_id.to_string(),
+                    None => return,
+                };
+                if let Ok(image) = body.decoded_body(headers) {
+                    images.insert(content_id, (media_type, image));
+                }
+            });
+        }
+        images
+    }
+
+    /// The part with the given `Content-ID`, for resolving `cid:` URLs when rendering HTML.
+    ///
+    /// `cid` may be a `cid:` URL, whose percent-escapes are decoded
+    /// ([RFC 2392](https://tools.ietf.org/html/rfc2392)), or a content ID with or without its `<>`.
+    /// Content IDs are compared exactly, including case. The part's body holds the content, e.g.
+    /// an image to be decoded with [`Section::decoded_body`].
+    ///
+    /// ```
+    /// use crumble::Message;
+    /// let message = Message::new("Content-Type: multipart/related; boundary=x\n\n--x\nContent-Type: text/html\n\n<img src=\"cid:logo@example.com\">\n--x\nContent-Type: image/png\nContent-ID: <logo@example.com>\n\n\n--x--\n").unwrap();
+    /// let image = message.find_by_content_id("cid:logo@example.com").unwrap();
+    /// assert_eq!(image.content_type(), Some("image/png"));
+    /// ```
+    pub fn find_by_content_id(&self, cid: &str) -> Option<&Section> {
+        let cid = cid.trim();
+        let cid = match cid.get(..4) {
+            Some(scheme) if scheme.eq_ignore_ascii_case("cid:") => {
+                let decoded = decode::decode_percent(&cid[4..]).ok()?;
+                String::from_utf8(decoded).ok()?
+            },
+            _ => cid.strip_prefix('<').and_then(|id| id.strip_suffix('>')).unwrap_or(cid).to_string(),
+        };
+        self.walk().find(|section| section.content_id() == Some(cid.as_str()))
+    }
+
+    /// The decoded filenames of all attachments in the document, in order.
+    ///
+    /// A part is an attachment if its `Content-Disposition` is `attachment`. Its name is taken from
+    /// the disposition's filename (see [`ContentDisposition::filename`]), falling back to the
+    /// `name` parameter of its `Content-Type`. Attachments without either are skipped.
+    pub fn attachment_filenames(&self) -> Vec<String> {
+        let mut filenames = Vec::new();
+        for section in &self.sections {
+            section.walk(&mut |section| {
+                match section.content_disposition() {
+                    Some(disposition) if disposition.disposition == "attachment" => (),
+                    _ => return,
+                };
+                if let Some(filename) = section.filename() {
+                    filenames.push(filename);
+                }
+            });
+        }
+        filenames
+    }
+
+    /// The attachments in the document, in order, as their filenames and decoded contents.
+    ///
+    /// A part is an attachment if its `Content-Disposition` is `attachment`, or `inline` with a
+    /// filename. The filename is found as with [`Message::attachment_filenames`], and is `None`
+    /// if the part has none. Contents are decoded from the part's transfer encoding, as with
+    /// [`Section::decoded_body`]; an attachment which contains other parts, such as a forwarded
+    /// `message/rfc822`, is returned as its MIME text. Returns `Error::ParseError` if an attachment
+    /// can't be decoded, e.g. because of malformed base64.
+    ///
+    /// ```
+    /// use crumble::Message;
+    /// let message = Message::new("Content-Type: multipart/mixed; boundary=x\n\n--x\nContent-Type: text/plain\n\nSee attached\n--x\nContent-Disposition: attachment; filename=hello.txt\nContent-Transfer-Encoding: base64\n\nSGVsbG8=\n--x--\n").unwrap();
+    /// assert_eq!(message.attachments().unwrap(), vec![(Some(String::from("hello.txt")), b"Hello".to_vec())]);
+    /// ```
+    pub fn attachments(&self) -> Result<Vec<Attachment>, Error> {
+        let mut attachments = Vec::new();
+        for section in self.walk() {
+            let (headers, body) = match section {
+                Section::Multipart {headers, body, ..} => (headers, body),
+                _ => continue,
+            };
+            let filename = section.filename();
+            match section.content_disposition() {
+                Some(disposition) if disposition.disposition == "attachment" => (),
+                Some(disposition) if disposition.disposition == "inline" && filename.is_some() => (),
+                _ => continue,
+            };
+            let contents = match body.as_slice() {
+                [body] if matches!(**body, Section::Plain {..} | Section::Empty) => body.decoded_body(headers)?,
+                parts => {
+                    let mut contents = Vec::new();
+                    for part in parts {
+                        part.write_bytes(&mut contents, "\n", false);
+                    }
+                    contents
+                },
+            };
+            attachments.push((filename, contents));
+        }
+        Ok(attachments)
+    }
+
+    /// The mbox status flags and keywords added to the message by a local mail delivery agent.
+    ///
+    /// Messages without these headers have no flags set.
+    pub fn mbox_flags(&self) -> MboxFlags {
+        let mut flags = MboxFlags::default();
+        for key in &["Status", "X-Status"] {
+            for letter in self.headers.get(key).unwrap_or_default().chars() {
+                match letter {
+                    'R' => flags.read = true,
+                    'O' => flags.old = true,
+                    'A' => flags.answered = true,
+                    'D' => flags.deleted = true,
+                    'F' => flags.flagged = true,
+                    _ => (),
+                }
+            }
+        }
+        if let Some(keywords) = self.headers.get("X-Keywords") {
+            flags.keywords = keywords.split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|keyword| !keyword.is_empty())
+                .map(String::from)
+                .collect();
+        }
+        flags
+    }
+
+    /// Find the encrypted data part of a PGP/MIME (`multipart/encrypted`) message.
+    ///
+    /// Returns the `application/octet-stream` section, which can be handed to an OpenPGP
+    /// implementation for decryption. Returns `None` if this is not an encrypted message.
+    pub fn encrypted_payload(&self) -> Option<&Section> {
+        if media_type(&self.headers)? != "multipart/encrypted" {
+            return None;
+        }
+        self.sections.iter().find(|section| media_type(section.headers()).as_deref() == Some("application/octet-stream"))
+    }
+
+    /// Classify an S/MIME message by its top-level content type.
+    ///
+    /// `application/pkcs7-mime` messages are classified by their `smime-type` parameter, and
+    /// `multipart/signed` messages with a `pkcs7-signature` protocol are detached signatures. As
+    /// with [`Message::encrypted_payload`], nothing is decrypted or verified. Returns `None` if
+    /// this is not an S/MIME message.
+    pub fn smime_type(&self) -> Option<SmimeKind> {
+        match media_type(&self.headers)?.as_str() {
+            "application/pkcs7-mime" | "application/x-pkcs7-mime" => {
+                let kind = match content_type_param(&self.headers, "smime-type").map(|t| t.to_lowercase()).as_deref() {
+                    Some("enveloped-data") => SmimeKind::EnvelopedData,
+                    Some("authenveloped-data") => SmimeKind::AuthEnvelopedData,
+                    Some("signed-data") => SmimeKind::SignedData,
+                    Some("compressed-data") => SmimeKind::CompressedData,
+                    Some("certs-only") => SmimeKind::CertsOnly,
+                    _ => SmimeKind::Unknown,
+                };
+                Some(kind)
+            },
+            "multipart/signed" => {
+                match content_type_param(&self.headers, "protocol")?.to_lowercase().as_str() {
+                    "application/pkcs7-signature" | "application/x-pkcs7-signature" => Some(SmimeKind::DetachedSignature),
+                    _ => None,
+                }
+            },
+            _ => None,
+        }
+    }
+
+    /// Iterate over every section in the document, in depth-first order.
+    ///
+    /// Each section is yielded before the sections nested in it, so containers, the parts within
+    /// them and their bodies are all visited.
+    ///
+    /// ```
+    /// use crumble::Message;
+    /// let message = Message::new("Content-Type: multipart/mixed; boundary=x\n\n--x\nContent-Type: text/plain\n\nHello\n--x--").unwrap();
+    /// let plain = message.walk().filter(|section| section.content_type() == Some("text/plain")).count();
+    /// assert_eq!(plain, 1);
+    /// ```
+    pub fn walk(&self) -> impl Iterator<Item = &Section> {
+        Sections {
+            stack: self.sections.iter().rev().collect(),
+        }
+    }
+
+    /// Every section whose `content-type` matches a `type/subtype` pattern, in depth-first order.
+    ///
+    /// Matching ignores case, and the pattern may end with a `*` wildcard, as with
+    /// [`ContentType::matches`]. Sections without a content type are not matched, even though they
+    /// are implicitly `text/plain`.
+    ///
+    /// ```
+    /// use crumble::Message;
+    /// let message = Message::new("Content-Type: multipart/alternative; boundary=x\n\n--x\nContent-Type: text/plain\n\nHello\n--x\nContent-Type: text/html\n\n<p>Hello</p>\n--x--").unwrap();
+    /// assert_eq!(message.find_sections("text/html").len(), 1);
+    /// assert_eq!(message.find_sections("TEXT/*").len(), 2);
+    /// ```
+    pub fn find_sections(&self, mime_type: &str) -> Vec<&Section> {
+        self.walk()
+            .filter(|section| section.parsed_content_type().is_some_and(|content_type| content_type.matches(mime_type)))
+            .collect()
+    }
+
+    /// The richest representation offered by the document's first `multipart/alternative`, for
+    /// display.
+    ///
+    /// The alternative may be the message itself or a part nested in it, e.g. in a
+    /// `multipart/mixed` with attachments. HTML is preferred, either a `text/html` part or a
+    /// `multipart/related` part holding HTML with the images it references, then `text/plain`,
+    /// then anything else. Between equally preferred parts the last wins, as alternatives are
+    /// ordered from least to most faithful
+    /// ([RFC 2046 section 5.1.4](https://tools.ietf.org/html/rfc2046#section-5.1.4)). Returns
+    /// `None` if there is no alternative.
+    ///
+    /// ```
+    /// use crumble::Message;
+    /// let message = Message::new("Content-Type: multipart/alternative; boundary=x\n\n--x\nContent-Type: text/plain\n\nHello\n--x\nContent-Type: text/html\n\n<p>Hello</p>\n--x--").unwrap();
+    /// assert_eq!(message.best_alternative().unwrap().content_type(), Some("text/html"));
+    /// ```
+    pub fn best_alternative(&self) -> Option<&Section> {
+        let alternatives: Vec<&Section> = if media_type(&self.headers).as_deref() == Some("multipart/alternative") {
+            self.sections.iter().collect()
+        } else {
+            match self.walk().find(|section| section.multipart_subtype().as_deref() == Some("alternative"))? {
+                Section::Multipart {body, ..} => body.iter().map(|section| &**section).collect(),
+                _ => return None,
+            }
+        };
+        let preference = |section: &Section| match media_type(section.headers()).as_deref() {
+            Some("text/html") | Some("multipart/related") => 2,
+            Some("text/plain") => 1,
+            _ => 0,
+        };
+        alternatives.into_iter()
+            .filter(|section| **section != Section::Empty)
+            .enumerate()
+            .max_by_key(|(index, section)| (preference(section), *index))
+            .map(|(_, section)| section)
+    }
+
+    /// Drive a [`Visitor`] over every section of the document. Top-level sections are visited at
+    /// depth 0; the message's own headers are not visited.
+    pub fn visit(&self, visitor: &mut impl Visitor) {
+        for section in &self.sections {
+            section.visit(visitor);
+        }
+    }
+
+    /// Iterate mutably over the innermost parts of the document, in depth-first order.
+    ///
+    /// A section is yielded if it has no nested multipart sections: a part with headers and a
+    /// body, or a bare `Plain` or `Empty` section. Multipart containers of other parts are not
+    /// yielded themselves, since their children are yielded instead, but their plain children are.
+    ///
+    /// ```
+    /// use crumble::{Message, Section};
+    /// let mut message = Message::new("Content-Type: multipart/mixed; boundary=x\n\n--x\nContent-Type: text/plain\n\nSecret\n--x--").unwrap();
+    /// for section in message.iter_sections_mut() {
+    ///     if section.content_type() == Some("text/plain") {
+    ///         *section = Section::Empty;
+    ///     }
+    /// }
+    /// assert_eq!(message.sections[0], Section::Empty);
+    /// ```
+    pub fn iter_sections_mut(&mut self) -> impl Iterator<Item = &mut Section> {
+        SectionsMut {
+            stack: self.sections.iter_mut().rev().collect(),
+        }
+    }
+
+    /// Remove every `Section::Empty` from the document, at any depth.
+    ///
+    /// Afterwards no `Empty` section remains anywhere in `sections`, so callers need not skip
+    /// them. A multipart section whose only part was empty is kept, with an empty body. The output
+    /// of [`Message::to_bytes`] is unchanged, since the closing delimiter is written regardless,
+    /// but the pruned message no longer compares equal to one parsed back from it.
+    ///
+    /// ```
+    /// use crumble::{Message, Section};
+    /// let mut message = Message::new("Content-Type: multipart/mixed; boundary=x\n\n--x\nContent-Type: text/plain\n\nHello\n--x--").unwrap();
+    /// assert_eq!(message.sections.last(), Some(&Section::Empty));
+    /// message.prune_empty();
+    /// assert_eq!(message.sections.len(), 1);
+    /// ```
+    pub fn prune_empty(&mut self) {
+        self.sections.retain(|section| *section != Section::Empty);
+        for section in &mut self.sections {
+            section.prune_empty();
+        }
+    }
+
+    /// Check the parsed document against the MIME specification.
+    ///
+    /// Returns a warning for each place where the permissive parser had to recover from a
+    /// noncompliant document. An empty result does not guarantee that the document is compliant.
+    pub fn validate(&self) -> Vec<Warning> {
+        let mut warnings = Vec::new();
+        validate_headers(&self.headers, &mut warnings);
+        // The body of a plain message is encoded according to the message headers
+        if let [body] = self.sections.as_slice() {
+            body.validate_transfer_encoding(&self.headers, &mut warnings);
+        }
+        for section in &self.sections {
+            section.validate(&mut warnings);
+        }
+        warnings
+    }
+
+    /// Whether the first `Content-Type` header declares a multipart type with a usable boundary.
+    ///
+    /// Only the header is checked, not how the body was parsed: a message whose boundary never
+    /// appears in the body is parsed as plain, as described in [`Message::new`], but is still
+    /// multipart here.
+    pub fn is_multipart(&self) -> bool {
+        self.headers.get("Content-Type").is_some_and(is_multipart_type)
+    }
+
+    // The boundary of a raw document which is to be parsed as multipart.
+    fn declared_boundary(raw_message: &[u8]) -> Option<String> {
+        // Most messages are plain, so avoid parsing the headers unless they could declare a
+        // multipart type
+        let raw_headers = header_block(raw_message);
+        if !raw_headers.windows(9).any(|word| word.eq_ignore_ascii_case(b"multipart")) {
+            return None;
+        }
+        let content_type = declared_content_type(raw_headers)?;
+        if !is_multipart_type(&content_type) {
+            return None;
+        }
+        boundary_param(&content_type)
+    }
+}
+
+/// A message which borrows its bodies from the document it was parsed from. See [`SectionRef`].
+///
+/// Messages are compared by their headers and sections, as with [`Message`].
+#[derive(Debug, Clone)]
+pub struct MessageRef<'a> {
+    pub headers: Headers,
+    pub sections: Vec<SectionRef<'a>>,
+    raw_headers: &'a [u8],
+    preamble: &'a [u8],
+}
+
+impl PartialEq for MessageRef<'_> {
+    fn eq(&self, other: &MessageRef) -> bool {
+        self.headers == other.headers && self.sections == other.sections
+    }
+}
+
+impl From<MessageRef<'_>> for Message {
+    fn from(message: MessageRef) -> Message {
+        message.into_owned()
+    }
+}
+
+impl<'a> MessageRef<'a> {
+    /// Parse a MIME document, as with [`Message::from_bytes`], borrowing bodies from `raw_message`.
+    pub fn new(raw_message: &'a [u8]) -> Result<MessageRef<'a>, Error> {
+        MessageRef::with_options(raw_message, &ParseOptions::default())
+    }
+
+    /// Parse a MIME document, as with [`MessageRef::new`], using the given options.
+    ///
+    /// [`ParseOptions::retain_source`] has no effect, as the document is borrowed anyway.
+    pub fn with_options(raw_message: &'a [u8], options: &ParseOptions) -> Result<MessageRef<'a>, Error> {
+        if options.max_len.is_some_and(|max_len| raw_message.len() > max_len) {
+            return Err(Error::TooLarge);
+        }
+
+        let context = Context {
+            source: raw_message,
+            options,
+            depth: 0,
+        };
+
+        // Blank lines or whitespace before the first header, e.g. left by naively concatenating
+        // documents, would otherwise be read as an empty header block. Input which doesn't start
+        // with a header is left alone, so a document with no headers is still reported as such.
+        let start = raw_message.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(raw_message.len());
+        let raw_message = match header_key_colon(&raw_message[start..]) {
+            Some(_) => &raw_message[start..],
+            None => raw_message,
+        };
+
+        // Multipart and plain messages require entirely different parsing pathways
+        match Message::declared_boundary(raw_message) {
+            Some(boundary) => MessageRef::parse_multipart(raw_message, &boundary, &context),
+            None => MessageRef::parse_plain(raw_message, &context),
+        }
+    }
+
+    /// Copy the bodies into an owned [`Message`].
+    pub fn into_owned(self) -> Message {
+        Message {
+            headers: self.headers,
+            sections: self.sections.into_iter().map(SectionRef::into_owned).collect(),
+            raw_headers: String::from_utf8_lossy(self.raw_headers).into_owned(),
+            preamble: String::from_utf8_lossy(self.preamble).into_owned(),
+            source: None,
+            options: ParseOptions::default(),
+        }
+    }
+
+    fn parse_plain(raw_message: &'a [u8], context: &Context) -> Result<MessageRef<'a>, Error> {
+        // Plain messages separate the headers from the body with more than 2 newlines
+        let (raw_headers, body) = match split_blank_line(raw_message) {
+            (_, None) => return Err(Error::MissingSeparator),
+            ([], _) => return Err(Error::EmptyHeaders),
+            (raw_headers, Some(body)) => (raw_headers, body),
+        };
+
+        let headers = context.headers(raw_headers)?;
+
+        // Everything after the header is by definition the body. There is only one section, which
+        // is Empty if the message is only headers.
+        let sections = vec![SectionRef::parse(body, context)?];
+
+        Ok(MessageRef {
+            headers,
+            sections,
+            raw_headers,
+            preamble: &[],
+        })
+    }
+
+    fn parse_multipart(raw_message: &'a [u8], boundary: &str, context: &Context) -> Result<MessageRef<'a>, Error> {
+        // Multipart messages separate parts using a boundary string, defined in the main headers
+        let boundary = format!("--{}", boundary);
+        let (raw_parts, epilogue) = split_boundary(raw_message, boundary.as_bytes());
+
+        // Without a single delimiter the body can't be split, so it is read as a plain message
+        if raw_parts.len() == 1 && epilogue.is_none() {
+            return MessageRef::parse_plain(raw_message, context);
+        }
+
+        // Text between the headers and the first delimiter is preamble, which is kept apart so that
+        // a line in it like "Note: ..." isn't read as a header
+        let (raw_headers, preamble) = split_blank_line(raw_parts[0]);
+        let headers = context.headers(raw_headers)?;
+
+        // The closing delimiter becomes a trailing Empty section, and the epilogue after it is
+        // dropped. If the closing delimiter is missing, the last part runs to the end of the
+        // document.
+        let mut sections = Vec::new();
+
+        // Parse each section
+        for section in &raw_parts[1..] {
+            let section = SectionRef::parse(section, context)?; // Note that this constructor will recursively build sections, as required
+            sections.push(section);
+        }
+        if epilogue.is_some() {
+            sections.push(SectionRef::Empty);
+        }
+
+        Ok(MessageRef {
+            headers,
+            sections,
+            raw_headers: header_block(raw_headers),
+            preamble: preamble.unwrap_or_default(),
+        })
+    }
+}
+
+// Infallible construction, for tests and examples only. Keep out of the fallible API above.
+impl Message {
+    /// Parse a document which is known to be valid, panicking otherwise.
+    ///
+    /// This keeps tests and examples terse, like `Regex::new(...).unwrap()`. Use [`Message::new`]
+    /// for input which may be malformed.
+    ///
+    /// # Panics
+    /// Panics if the document cannot be parsed.
+    pub fn from_str_unchecked(raw: &str) -> Message {
+        match Message::new(raw) {
+            Ok(message) => message,
+            Err(e) => panic!("Could not parse message: {:?}", e),
+        }
+    }
+}
+
+// The header block at the start of a raw document or section: everything up to the first blank line,
+// ignoring line endings left over from a preceding boundary.
+fn header_block(raw: &[u8]) -> &[u8] {
+    let start = raw.iter().position(|&b| b != b'\n' && b != b'\r').unwrap_or(raw.len());
+    let raw = &raw[start..];
+    match find_blank_line(raw) {
+        Some((start, _)) => &raw[..start],
+        None => raw.trim_ascii_end(),
+    }
+}
+
+// Whether a Content-Type value is a multipart type. Without a usable boundary the parts can't be
+// separated, so the body is treated as plain.
+fn is_multipart_type(content_type: &str) -> bool {
+    content_type.trim_start().to_lowercase().starts_with("multipart") && boundary_param(content_type).is_some()
+}
+
+// Whether a part with these headers holds an embedded message (RFC 2046 section 5.2.1, and
+// RFC 6532 for internationalised messages).
+fn is_message_type(headers: &Headers) -> bool {
+    matches!(media_type(headers).as_deref(), Some("message/rfc822") | Some("message/global"))
+}
+
+// The Content-Type declared in a block of headers. Only the first Content-Type header counts.
+fn declared_content_type(raw_headers: &[u8]) -> Option<String> {
+    parse_headers(&String::from_utf8_lossy(raw_headers), &ParseOptions::default()).ok()?.get("Content-Type").map(str::to_string)
+}
+
+// Collect the text of a part with the given headers and body sections, for Message::text_content.
+fn collect_text(headers: &Headers, sections: Vec<&Section>, texts: &mut Vec<String>) {
+    // Parts without a Content-Type are plain text
+    let content_type = media_type(headers).unwrap_or_else(|| String::from("text/plain"));
+    match content_type.as_str() {
+        "text/plain" | "text/html" => {
+            for section in sections {
+                if let Section::Plain {..} = section {
+                    let text = match section.decoded_text_in(headers) {
+                        Ok(text) if content_type == "text/html" => strip_tags(&text),
+                        Ok(text) => text,
+                        Err(_) => continue,
+                    };
+                    if !text.trim().is_empty() {
+                        texts.push(text.trim().to_string());
+                    }
+                }
+            }
+        },
+        _ => {
+            // Alternatives with a plain text version don't need their HTML version
+            let has_plain = content_type == "multipart/alternative"
+                && sections.iter().any(|section| media_type(section.headers()).as_deref() == Some("text/plain"));
+            for section in sections {
+                if has_plain && media_type(section.headers()).as_deref() == Some("text/html") {
+                    continue;
+                }
+                if let Section::Multipart {headers, body, ..} = section {
+                    collect_text(headers, body.iter().map(|section| &**section).collect(), texts);
+                }
+            }
+        },
+    }
+}
+
+// Approximate the text of an HTML document by removing scripts, styles and tags.
+fn strip_tags(html: &str) -> String {
+    lazy_static! {
+        static ref HIDDEN: Regex = Regex::new(r"(?is)<(script|style)\b.*?</(script|style)\s*>").unwrap();
+        static ref TAG: Regex = Regex::new(r"(?s)<[^>]*>").unwrap();
+    }
+    let text = HIDDEN.replace_all(html, "");
+    let text = TAG.replace_all(&text, "");
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+// Compare headers as a multiset of lowercased keys and values with whitespace runs collapsed.
+fn headers_eq_structural(a: &Headers, b: &Headers) -> bool {
+    let normalize = |headers: &Headers| {
+        let mut normalized: Vec<(String, String)> = headers.iter()
+            .map(|header| (header.key.to_lowercase(), header.value.split_whitespace().collect::<Vec<&str>>().join(" ")))
+            .collect();
+        normalized.sort();
+        normalized
+    };
+    a.len() == b.len() && normalize(a) == normalize(b)
+}
+
+// Normalise line endings to \n and strip trailing whitespace from each line and the whole body.
+fn normalize_body(body: &[u8]) -> Vec<u8> {
+    let mut normalized = Vec::with_capacity(body.len());
+    for line in body.split(|&b| b == b'\n') {
+        let end = line.iter().rposition(|b| !b.is_ascii_whitespace()).map_or(0, |i| i + 1);
+        normalized.extend_from_slice(&line[..end]);
+        normalized.push(b'\n');
+    }
+    let end = normalized.iter().rposition(|b| !b.is_ascii_whitespace()).map_or(0, |i| i + 1);
+    normalized.truncate(end);
+    normalized
+}
+
+// True if needle occurs anywhere in haystack. Everything contains the empty needle.
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    needle.is_empty() || haystack.windows(needle.len()).any(|window| window == needle)
+}
+
+// Join a folded header value back onto one line (RFC 5322 section 2.2.3). Each line break before a
+// continuation line, with the whitespace starting that line, becomes a single space. This is shared
+// by every accessor which reads a header value, so they all agree.
+pub(crate) fn unfold(value: &str) -> String {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"\r?\n[ \t]+").unwrap();
+    }
+    RE.replace_all(value, " ").into_owned()
+}
+
+// Split a multipart body on a delimiter line, like str::split but only where the delimiter is a
+// whole line: --<boundary>, or --<boundary>-- to close, optionally followed by whitespace.
+// Elsewhere, e.g. in the Content-Type header declaring the boundary, in the middle of a line, or as
+// the start of a longer boundary, it is just text. The rest of a delimiter line, i.e. any transport
+// padding and the line ending, belongs to the delimiter rather than the following part, so it is
+// dropped.
+// Splitting stops at the closing delimiter, and the epilogue after it is returned separately, so
+// callers know whether the body was closed without guessing from the content of the last piece. A
+// part may well start with -- (e.g. a "-- " signature). Without a closing delimiter the last piece
+// runs to the end of the input, and there is no epilogue.
+fn split_boundary<'a>(raw: &'a [u8], delimiter: &[u8]) -> (Vec<&'a [u8]>, Option<&'a [u8]>) {
+    let mut pieces = Vec::new();
+    let mut last = 0;
+    let mut line = 0;
+    while line < raw.len() {
+        let end = match raw[line..].iter().position(|&b| b == b'\n') {
+            Some(newline) => line + newline + 1,
+            None => raw.len(),
+        };
+        if let Some(rest) = raw[line..end].strip_prefix(delimiter) {
+            let (closing, rest) = match rest.strip_prefix(b"--") {
+                Some(rest) => (true, rest),
+                None => (false, rest),
+            };
+            if rest.iter().all(|b| b.is_ascii_whitespace()) {
+                pieces.push(&raw[last..line]);
+                if closing {
+                    return (pieces, Some(&raw[end..]));
+                }
+                last = end;
+            }
+        }
+        line = end;
+    }
+    pieces.push(&raw[last..]);
+    (pieces, None)
+}
+
+// The boundary parameter of a Content-Type value, if it is usable.
+// https://tools.ietf.org/html/rfc2046#section-5.1.1
+// Parameters are split as for any other Content-Type parameter, so a repeated boundary takes its
+// last value. A quoted boundary may contain spaces (e.g. "simple boundary") and escapes; otherwise
+// the boundary runs to the next whitespace. ' may appear in a boundary, so single quotes only count
+// as quoting when they enclose the whole value.
+// The boundary is always matched literally (see split_boundary), never as a pattern, so
+// punctuation such as + ? . ( ) has no special meaning.
+fn boundary_param(content_type: &str) -> Option<String> {
+    let value = quoted_parameters(content_type).into_iter()
+        .rfind(|(key, _)| key == "boundary")
+        .map(|(_, value)| value)?;
+    // An unquoted boundary can't contain whitespace, so anything after it isn't part of it
+    let boundary = match value.starts_with('"') {
+        true => unquote(value),
+        false => unquote(value.split_whitespace().next()?),
+    };
+    if boundary.trim().is_empty() {
+        return None;
+    }
+    Some(boundary)
+}
+
+// The lowercased type/subtype from the Content-Type header, without parameters.
+fn media_type(headers: &Headers) -> Option<String> {
+    let content_type = unfold(headers.get("Content-Type")?);
+    let media_type = content_type.split(|c: char| c == ';' || c.is_whitespace()).next()?;
+    Some(media_type.to_lowercase())
+}
+
+// The value of the named Content-Type parameter. A repeated parameter takes its last value.
+fn content_type_param(headers: &Headers, key: &str) -> Option<String> {
+    let content_type = headers.get("Content-Type")?;
+    parse_parameters(content_type).into_iter()
+        .rfind(|(k, _)| k == key)
+        .map(|(_, v)| v)
+}
+
+// The transfer encoding mechanism, ignoring any trailing comment or parameters.
+fn transfer_encoding(headers: &Headers) -> Option<String> {
+    let value = unfold(headers.get("Content-Transfer-Encoding")?);
+    value.split(|c: char| c.is_whitespace() || c == ';' || c == '(')
+        .find(|token| !token.is_empty())
+        .map(|token| token.to_lowercase())
+}
+
+fn validate_headers(headers: &Headers, warnings: &mut Vec<Warning>) {
+    if headers.get_all("Content-Type").count() > 1 {
+        warnings.push(Warning::DuplicateContentType);
+    }
+    for header in headers {
+        let key = header.key.to_lowercase();
+        if (key == "content-type" || key == "content-disposition") && has_unseparated_parameters(&header.value) {
+            warnings.push(Warning::UnseparatedParameters(key));
+        }
+    }
+}
+
+// Find keys and values for each header
+fn parse_headers(raw_headers: &str, options: &ParseOptions) -> Result<Headers, Error> {
+    parse_header_bytes(raw_headers.as_bytes(), options)
+}
+
+// The position of the : ending the header key which starts this line, if it starts with one.
+fn header_key_colon(line: &[u8]) -> Option<usize> {
+    let key_len = line.iter().take_while(|&&b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-').count();
+    let colon = key_len + line[key_len..].iter().take_while(|&&b| b == b' ' || b == b'\t').count();
+    (key_len > 0 && line.get(colon) == Some(&b':')).then_some(colon)
+}
+
+// Parse a block of headers, recording spans relative to the start of the block. Values which are
+// not valid UTF-8 are converted lossily.
+fn parse_header_bytes(raw_headers: &[u8], options: &ParseOptions) -> Result<Headers, Error> {
+    // A MIME key is a string of letters|numbers|-|_, followed by a : (some generators put
+    // whitespace before the :, which is not part of the key)
+    // It starts on it's own line (i.e. after a \n), with no indentation: an indented line is a
+    // folded continuation of the previous value, even if it looks like a key (e.g. "\tid: foo")
+    // While the spec requires a header to be all on its own line,
+    // parsers in the wild (e.g. GMail) will split headers across mutliple lines.
+    // Hence, a value is everything between two keys.
+    // So, find the positions of all the keys -> extract key String
+    // Then, infer the position of text between subsequent keys -> extract value String
+
+    let mut header_indices: Vec<(usize,usize)> = Vec::new();
+
+    // Construct a vector of key positions, scanning each line by hand as this runs for every part
+    let mut line_start = 0;
+    while line_start < raw_headers.len() {
+        let line = &raw_headers[line_start..];
+        if let Some(colon) = header_key_colon(line) {
+            header_indices.push((line_start, line_start + colon)); // Strip off :
+        }
+        line_start += match line.iter().position(|&b| b == b'\n') {
+            Some(newline) => newline + 1,
+            None => line.len(),
+        };
+    }
+
+    // Convert key positions to key and value strings
+    let mut headers = Headers::new();
+    for (index, header) in header_indices.iter().enumerate() {
+        // The final value is not between two keys: it is final key to end of string.
+        // It may be empty, or not followed by a newline, so don't assume a space after the :
+        let end = match header_indices.get(index + 1) {
+            Some(next) => next.0,
+            None => raw_headers.len(),
+        };
+        let span = Span {
+            start: header.0,
+            end: header.0 + raw_headers[header.0..end].trim_ascii_end().len(),
+        };
+
+        // Everything between the : and the next key belongs to the value, including the leading
+        // space, folding whitespace, and trailing line ending. Folded values keep their line
+        // breaks, but as \n whatever the document used
+        let key = String::from_utf8_lossy(&raw_headers[header.0..header.1]);
+        let value = String::from_utf8_lossy(&raw_headers[header.1 + 1..end]);  // Correct for :
+        let mut header = Header::new_raw(&key, &value);
+        if !options.preserve_header_bytes {
+            header.raw = None;
         }
+        header.span = span;
+        headers.push(header);
     }
 
     Ok(headers)
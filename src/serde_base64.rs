@@ -0,0 +1,15 @@
+//! Serialise byte bodies as base64 strings, for the `serde` feature.
+//!
+//! Bodies may be in any charset or binary, so they can't be serialised as strings directly, and
+//! base64 is much more compact than an array of numbers in text formats such as JSON.
+
+use serde::{Deserialize, Deserializer, Serializer};
+
+pub(crate) fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&base64::encode(bytes))
+}
+
+pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let encoded = String::deserialize(deserializer)?;
+    base64::decode_config(&encoded, base64::STANDARD).map_err(serde::de::Error::custom)
+}
@@ -1,8 +1,10 @@
 #![cfg(test)]
+#![allow(clippy::vec_init_then_push)]
 
 extern crate test;
 
-use super::{Message, Section, Header};
+use super::{Error, Message, MessageRef, Section, SectionRef, Header, Headers, Parser, ParseOptions, Warning, ContentDisposition, ContentType, Span, SmimeKind, MboxFlags, Visitor};
+use super::{parse_parameters, parse_headers};
 use test::Bencher;
 
 fn prepare_file(filename: &str) -> String {
@@ -12,7 +14,7 @@ fn prepare_file(filename: &str) -> String {
 
     let path = Path::new(filename);
 
-    let mut file = match File::open(&path) {
+    let mut file = match File::open(path) {
         Ok(file) => file,
         Err(e) => panic!("Could not open plain email file: {:?}", e),
     };
@@ -65,7 +67,7 @@ fn empty_string() {
     let email = Message::new(empty_string);
     match email {
         Ok(_) => panic!("Successfully parsed empty string"),
-        Err(_e) => assert!(true) // InvalidString
+        Err(_e) => (), // MissingSeparator
     }
 }
 
@@ -76,10 +78,20 @@ fn bad_string() {
     let email = Message::new(bad_string);
     match email {
         Ok(_) => panic!("Successfully parsed bad string"),
-        Err(_e) => assert!(true) // InvalidString
+        Err(_e) => (), // MissingSeparator
     }
 }
 
+#[test]
+fn plain_parse_errors() {
+    let error = |raw: &[u8]| Message::from_bytes(raw).unwrap_err();
+    assert_eq!(error(b""), Error::MissingSeparator);
+    assert_eq!(error(b"Hello, world!"), Error::MissingSeparator);
+    assert_eq!(error(b"Subject: Hello"), Error::MissingSeparator);
+    assert_eq!(error(b"\n\nHello, world!"), Error::EmptyHeaders);
+    assert_eq!(Error::EmptyHeaders.to_string(), "Error parsing message: No headers");
+}
+
 #[bench]
 fn bench_plain(b: &mut Bencher) {
     let message = prepare_plain();
@@ -104,24 +116,44 @@ fn bench_bandcamp(b: &mut Bencher) {
     b.iter(|| Message::new(&message));
 }
 
+#[bench]
+fn bench_nested(b: &mut Bencher) {
+    let message = prepare_nested();
+    b.iter(|| Message::new(&message));
+}
+
+// Multipart parts nested in each other, `depth` deep, around a single text part.
+fn deeply_nested(depth: usize) -> String {
+    let mut message = String::from("Content-Type: text/plain\n\nHello\n");
+    for level in 0..depth {
+        message = format!("Content-Type: multipart/mixed; boundary=b{level}\n\n--b{level}\n{}--b{level}--\n", message, level = level);
+    }
+    format!("Subject: Deep\n{}", message)
+}
+
+#[bench]
+fn bench_deeply_nested(b: &mut Bencher) {
+    let message = deeply_nested(50);
+    b.iter(|| Message::new(&message));
+}
+
 #[test]
 fn build_plain() {
     let plain = prepare_plain();
     let message = Message::new(&plain).unwrap();
-    let reference = String::from(r#"message-id: <0123ABCD>
-subject: Hello, world!
-cc: user1@example.com,
+    let reference = String::from(r#"Message-ID: <0123ABCD>
+Subject: Hello, world!
+Cc: user1@example.com,
 user2@example.com
-to: user3@example.com
-from: user4@example.com
-date: 1997-07-16T19:30:30+01:00
-x-mailer: Foo Corp Widgets 12.0.3.1.20 Build 2020040302
+To: user3@example.com
+From: user4@example.com
+Date: 1997-07-16T19:30:30+01:00
+X-Mailer: Foo Corp Widgets 12.0.3.1.20 Build 2020040302
 type bar
 description baz
-x-mimetrack: Serialize by Foo
-mime-version: 1.0
-content-type: text/plain; charset=US-ASCII
-
+X-MIMETrack: Serialize by Foo
+MIME-Version: 1.0
+Content-type: text/plain; charset=US-ASCII
 
 Hello user3,
 
@@ -130,55 +162,50 @@ How is the moon?
 How are the stars?
 
 Cheers
-user4
-"#);
+user4"#);
     assert_eq!(message.to_string(), reference);
+    assert_eq!(Message::new(&message.to_string()).unwrap(), message);
 }
 
 #[test]
 fn build_gmail() {
     let gmail = prepare_gmail_alt();
     let message = Message::new(&gmail).unwrap();
-    let reference = String::from(r#"mime-version: 1.0
-from: Example <example@gmail.com>
-date: Tue, 10 Sep 2019 12:47:31 +1000
-message-id: <CAMUmi+mvKSB1x93x3su-+Yy1AvDNm2jFmgs6fVgMtGL35XuBCw@mail.gmail.com>
-subject: Example
-to: example@example.com
-content-type: multipart/mixed; boundary="0000000000008a01e4059229eec0"
+    let reference = String::from(r#"MIME-Version: 1.0
+From: Example <example@gmail.com>
+Date: Tue, 10 Sep 2019 12:47:31 +1000
+Message-ID: <CAMUmi+mvKSB1x93x3su-+Yy1AvDNm2jFmgs6fVgMtGL35XuBCw@mail.gmail.com>
+Subject: Example
+To: example@example.com
+Content-Type: multipart/mixed; boundary="0000000000008a01e4059229eec0"
 
 --0000000000008a01e4059229eec0
-content-type: multipart/alternative; boundary="0000000000008a01e1059229eebe"
+Content-Type: multipart/alternative; boundary="0000000000008a01e1059229eebe"
+
 --0000000000008a01e1059229eebe
-content-type: text/plain; charset="UTF-8"
+Content-Type: text/plain; charset="UTF-8"
 
 Hello, world!
 
-
-
 --0000000000008a01e1059229eebe
-content-type: text/html; charset="UTF-8"
+Content-Type: text/html; charset="UTF-8"
 
 <div dir="ltr">Hello, world!<br></div>
 
-
-
 --0000000000008a01e1059229eebe--
 --0000000000008a01e4059229eec0
-content-type: image/png; name="Lenna_(test_image).png"
-content-disposition: attachment; filename="Lenna_(test_image).png"
-content-transfer-encoding: base64
-content-id: <f_k0d8idqy0>
-x-attachment-id: f_k0d8idqy0
+Content-Type: image/png; name="Lenna_(test_image).png"
+Content-Disposition: attachment; filename="Lenna_(test_image).png"
+Content-Transfer-Encoding: base64
+Content-ID: <f_k0d8idqy0>
+X-Attachment-Id: f_k0d8idqy0
 
 <snip>
 
-
-
---0000000000008a01e4059229eec0
-
---0000000000008a01e4059229eec0--"#);
+--0000000000008a01e4059229eec0--
+"#);
     assert_eq!(message.to_string(), reference);
+    assert_eq!(Message::new(&message.to_string()).unwrap(), message);
 }
 
 #[test]
@@ -208,9 +235,9 @@ fn parse_plain() {
             assert_eq!(headers.len(), headers_reference.len());
             let mut index = 0;
             for header in headers {
-                assert_eq!(header.key, headers_reference[index].key.to_lowercase());
+                assert!(header.key.eq_ignore_ascii_case(&headers_reference[index].key));
                 assert_eq!(header.value, headers_reference[index].value);
-                index = index + 1;
+                index += 1;
             }
 
             let body = String::from("Hello user3,
@@ -221,12 +248,12 @@ How are the stars?
 
 Cheers
 user4");
-            let sections_reference = vec![Section::new(&body).unwrap()];
+            let sections_reference = [Section::new(&body).unwrap()];
             assert_eq!(sections.len(), sections_reference.len());
             index = 0;
             for section in sections {
                 assert_eq!(section, sections_reference[index]);
-                index = index + 1;
+                index += 1;
             }
 
         }
@@ -253,9 +280,9 @@ fn parse_multipart(){
             assert_eq!(headers.len(), headers_reference.len());
             let mut index = 0;
             for header in headers {
-                assert_eq!(header.key, headers_reference[index].key.to_lowercase());
+                assert!(header.key.eq_ignore_ascii_case(&headers_reference[index].key));
                 assert_eq!(header.value, headers_reference[index].value);
-                index = index + 1;
+                index += 1;
             }
 
             let mut sections_reference = Vec::new();
@@ -275,7 +302,7 @@ fn parse_multipart(){
             index = 0;
             for section in sections {
                 assert_eq!(section, sections_reference[index]);
-                index = index + 1;
+                index += 1;
             }
         }
     }
@@ -341,27 +368,30 @@ fn parse_gmail() {
             assert_eq!(headers.len(), headers_reference.len());
             let mut index = 0;
             for header in headers {
-                assert_eq!(header.key, headers_reference[index].key.to_lowercase());
+                assert!(header.key.eq_ignore_ascii_case(&headers_reference[index].key));
                 assert_eq!(header.value, headers_reference[index].value);
-                index = index + 1;
+                index += 1;
             }
 
             let mut sections_reference = Vec::new();
 
             let section = Section::Multipart {
-                headers: vec![Header::new("content-type", r#"multipart/alternative; boundary="0000000000008a01e1059229eebe""#)],
+                headers: vec![Header::new("content-type", r#"multipart/alternative; boundary="0000000000008a01e1059229eebe""#)].into(),
                 body: vec![
                     Box::new(Section::Multipart {
-                        headers: vec![Header::new("content-type", r#"text/plain; charset="UTF-8""#)],
-                        body: vec![Box::new(Section::Plain {body: String::from("Hello, world!\n\n").as_bytes().to_vec()})]
+                        headers: vec![Header::new("content-type", r#"text/plain; charset="UTF-8""#)].into(),
+                        body: vec![Box::new(Section::Plain {body: String::from("Hello, world!\n\n").as_bytes().to_vec(), span: Span::default()})],
+                        span: Span::default(),
                     }),
                     Box::new(Section::Multipart {
-                        headers: vec![Header::new("content-type", r#"text/html; charset="UTF-8""#)],
+                        headers: vec![Header::new("content-type", r#"text/html; charset="UTF-8""#)].into(),
                         body: vec![Box::new(Section::Plain {body: String::from(r#"<div dir="ltr">Hello, world!<br></div>
 
-"#).as_bytes().to_vec()})]
+"#).as_bytes().to_vec(), span: Span::default()})],
+                        span: Span::default(),
                     })
-                ]
+                ],
+                span: Span::default(),
             };
 
             sections_reference.push(section);
@@ -386,7 +416,7 @@ x-attachment-id: f_k0d8idqy0"#;
             index = 0;
             for section in sections {
                 assert_eq!(section, sections_reference[index]);
-                index = index + 1;
+                index += 1;
             }
         }
     }
@@ -434,16 +464,14 @@ fn parse_bandcamp(){
             headers_reference.push(Header::new("date", "Sun, 01 Sep 2019 18:47:17 +0000 (UTC)"));
             headers_reference.push(Header::new("x-sg-eid", "nBOqntU0yBFjPVlNdjQaY3wDu4yTqLEvn1WO8Aw6GMB2LHOJyurZxQWDoS1ERDO7yQvQFeG32M8BCi
  laaSf8u02bkFHbT0Xv9H7gaVogCrkLtZogborUCORVUiPJGhw1UI+m13mfwglDAtiJrT1f96VpGs2T
- wwX20QhrGjGO2pMraVi2fI6k33Jlzv+pQ6HNut2ksNDg06CgBBC6mnB3KA==
-
-This is a multi-part message in MIME format."));
+ wwX20QhrGjGO2pMraVi2fI6k33Jlzv+pQ6HNut2ksNDg06CgBBC6mnB3KA=="));
 
             assert_eq!(headers.len(), headers_reference.len());
             let mut index = 0;
             for header in headers {
-                assert_eq!(header.key, headers_reference[index].key.to_lowercase());
+                assert!(header.key.eq_ignore_ascii_case(&headers_reference[index].key));
                 assert_eq!(header.value, headers_reference[index].value);
-                index = index + 1;
+                index += 1;
             }
 
             let mut sections_reference = Vec::new();
@@ -530,7 +558,7 @@ an>=0D
             index = 0;
             for section in sections {
                 assert_eq!(section, sections_reference[index]);
-                index = index + 1;
+                index += 1;
             }
         }
     }
@@ -562,54 +590,50 @@ fn parse_nested() {
             assert_eq!(headers.len(), headers_reference.len());
             let mut index = 0;
             for header in headers {
-                assert_eq!(header.key, headers_reference[index].key.to_lowercase());
+                assert!(header.key.eq_ignore_ascii_case(&headers_reference[index].key));
                 assert_eq!(header.value, headers_reference[index].value);
-                index = index + 1;
+                index += 1;
             }
 
             let mut sections_reference = Vec::new();
 
             let section = Section::Multipart {
-                headers: vec![Header::new("content-type", r#"multipart/alternative; boundary="boundary_B"
-
-Level A"#)],
+                headers: vec![Header::new("content-type", r#"multipart/alternative; boundary="boundary_B""#)].into(),
                 body: vec![
                     Box::new(Section::Multipart {
-                        headers: vec![Header::new("content-type", r#"multipart/alternative; boundary="boundary_C1"
-
-Level B1"#)],
+                        headers: vec![Header::new("content-type", r#"multipart/alternative; boundary="boundary_C1""#)].into(),
                         body: vec![
                             Box::new(Section::Multipart {
-                                headers: vec![Header::new("content-type", r#"multipart/alternative; boundary="boundary_D1"
-
-Level C1"#)],
+                                headers: vec![Header::new("content-type", r#"multipart/alternative; boundary="boundary_D1""#)].into(),
                                 body: vec![
                                     Box::new(Section::Multipart {
-                                        headers: vec![Header::new("content-type", r#"text/plain; charset="UTF-8""#)],
+                                        headers: vec![Header::new("content-type", r#"text/plain; charset="UTF-8""#)].into(),
                                         body: vec![
-                                            Box::new(Section::Plain{body: "Level D1\n\n".as_bytes().to_vec()})
-                                        ]
+                                            Box::new(Section::Plain{body: "Level D1\n\n".as_bytes().to_vec(), span: Span::default()})
+                                        ],
+                                        span: Span::default(),
                                     })
-                                ]
-
+                                ],
+                                span: Span::default(),
                             })
-                        ]
+                        ],
+                        span: Span::default(),
                     }),
                     Box::new(Section::Multipart {
-                        headers: vec![Header::new("content-type", r#"multipart/alternative; boundary="boundary_C2"
-
-Level B2"#)],
+                        headers: vec![Header::new("content-type", r#"multipart/alternative; boundary="boundary_C2""#)].into(),
                         body: vec![
                             Box::new(Section::Multipart {
-                                headers: vec![Header::new("content-type", r#"text/plain; charset="UTF-8""#)],
+                                headers: vec![Header::new("content-type", r#"text/plain; charset="UTF-8""#)].into(),
                                 body: vec![
-                                    Box::new(Section::Plain{body: "Level C2\n\n".as_bytes().to_vec()})
-                                ]
-
+                                    Box::new(Section::Plain{body: "Level C2\n\n".as_bytes().to_vec(), span: Span::default()})
+                                ],
+                                span: Span::default(),
                             })
-                        ]
+                        ],
+                        span: Span::default(),
                     })
-                ]
+                ],
+                span: Span::default(),
             };
 
             sections_reference.push(section);
@@ -621,7 +645,7 @@ Level B2"#)],
             index = 0;
             for section in sections {
                 assert_eq!(section, sections_reference[index]);
-                index = index + 1;
+                index += 1;
             }
 
         }
@@ -632,7 +656,7 @@ Level B2"#)],
 fn parse_pubkey() {
     let message = prepare_pubkey();
 
-    let email = Message::new(&message);
+    let _email = Message::new(&message);
     // 
     // match email {
     //     Err(e) => panic!("Could not parse email: {:?}", e),
@@ -641,3 +665,2306 @@ fn parse_pubkey() {
     //     }
     // }
 }
+
+#[test]
+fn parse_raw_headers() {
+    let message = prepare_gmail();
+    let options = ParseOptions { preserve_header_bytes: true, ..Default::default() };
+
+    let email = Message::with_options(&message, &options).unwrap();
+
+    let dkim = email.headers.iter().find(|h| h.key == "DKIM-Signature").unwrap();
+    assert!(dkim.value.starts_with("v=1; a=rsa-sha256;") && dkim.value.ends_with("Oeug=="));
+    assert_eq!(dkim.raw.as_deref().unwrap(), "DKIM-Signature: v=1; a=rsa-sha256; c=relaxed/relaxed;
+        d=gmail.com; s=20161025;
+        h=mime-version:from:date:message-id:subject:to;
+        bh=lw4nVU4tXnj+HUyblPpuT7Q2zwTdNrM3vDBj+iwz9SQ=;
+        b=pT2jMVu+581TAUDVdVuXaRvMdKY3QnrWOBtk9S4MacZFtbLrKwXEaxZGcoH2yl4xdF
+         duzdF4CItIGPKFR4hCUIQe2Vq0mdF42Z5XzECuVkzpoE+TWQ3A45LDvuaY9yxiGVZ/g3
+         ga+zQhibRirauw/zdudf5wWZx4CqQzNSY+USppi5VzvDCFbjAXeYXzzed9+8W23VWGN1
+         1zYkJZyg2WbEOMO/O2eueQ1w4y+qN4j+C37HzZeAOtv/h+00tCDQVEDg92pxC22hFm+b
+         sRuqOWmMvEtZ4swGH9etW75GUDaJWnHhf7yBHEsVq1EjfGLK6eVQ99JCSQxbv5z7/N+y
+         Oeug==
+");
+    assert_eq!(Header::new_raw("Key ", " value\r\n more\r\n"), Header { key: String::from("Key"), value: String::from("value\n more"), raw: Some(String::from("Key : value\r\n more\r\n")), span: Span::default() });
+    assert_eq!(Header::new(" Key", " value \n"), Header { key: String::from(" Key"), value: String::from(" value \n"), raw: None, span: Span::default() });
+    assert_eq!(Message::new(&message).unwrap().headers, email.headers);
+
+    // The accessors see the same values with the option on
+    let message = "Content-Type: multipart/alternative;\r\n boundary=\"x\"\r\nSubject: Hi\r\n\r\n--x\r\nContent-Type: text/plain\r\n\r\nHello\r\n--x\r\nContent-Type: text/html\r\n\r\n<p>Hello</p>\r\n--x--\r\n";
+    let plain = Message::new(message).unwrap();
+    let raw = Message::with_options(message, &options).unwrap();
+    assert_eq!(raw.headers[0].raw.as_deref(), Some("Content-Type: multipart/alternative;\r\n boundary=\"x\"\r\n"));
+    assert_eq!(raw.content_type(), plain.content_type());
+    assert_eq!(raw.content_type().unwrap().subtype, "alternative");
+    assert_eq!(raw.sections[0].content_type(), Some("text/plain"));
+    assert_eq!(raw.text_content(), "Hello");
+    assert_eq!(raw.best_alternative(), plain.best_alternative());
+    assert!(raw.best_alternative().is_some());
+    assert_eq!(raw.debug_tree(), plain.debug_tree());
+    assert_eq!(raw.to_bytes(), plain.to_bytes());
+    assert_eq!(Message::from_bytes(&raw.to_bytes()).unwrap(), plain);
+}
+
+#[test]
+fn parse_space_separated_parameters() {
+    let message = prepare_file("test/space_params");
+
+    let email = Message::new(&message).unwrap();
+    assert_eq!(email.headers.boundary(), Some(String::from("space_separated_boundary")));
+    assert_eq!(email.sections.len(), 2);
+
+    match &email.sections[0] {
+        Section::Multipart {headers, ..} => {
+            let ct = headers.get("content-type").unwrap();
+            assert_eq!(parse_parameters(ct), vec![
+                (String::from("charset"), String::from("utf-8")),
+                (String::from("format"), String::from("flowed")),
+            ]);
+        },
+        s => panic!("Expected multipart section, got {:?}", s),
+    }
+
+    assert_eq!(email.validate(), vec![
+        Warning::UnseparatedParameters(String::from("content-type")),
+        Warning::UnseparatedParameters(String::from("content-type")),
+    ]);
+    assert_eq!(Message::new(&prepare_multipart()).unwrap().validate(), vec![]);
+}
+
+#[test]
+fn header_bytes_len() {
+    let plain = prepare_plain();
+    let message = Message::new(&plain).unwrap();
+    assert!(message.raw_headers().starts_with("Message-ID: <0123ABCD>\n"));
+    assert!(message.raw_headers().ends_with("Content-type: text/plain; charset=US-ASCII"));
+    assert_eq!(message.header_bytes_len(), plain.find("\n\n").unwrap());
+
+    let bandcamp = prepare_bandcamp();
+    let message = Message::new(&bandcamp).unwrap();
+    assert!(message.raw_headers().ends_with("wwX20QhrGjGO2pMraVi2fI6k33Jlzv+pQ6HNut2ksNDg06CgBBC6mnB3KA=="));
+    assert_eq!(message.header_bytes_len(), bandcamp.find("\n\nThis is a multi-part message").unwrap());
+
+    let multipart = prepare_multipart();
+    let message = Message::new(&multipart).unwrap();
+    assert_eq!(message.header_bytes_len(), multipart.find("\n--XXXXboundary_text").unwrap());
+}
+
+#[test]
+fn decode_base64_at_boundary() {
+    use std::io::prelude::*;
+    use std::fs::File;
+
+    let message = prepare_file("test/base64_tight");
+    let email = Message::new(&message).unwrap();
+
+    let mut png = Vec::new();
+    File::open("test/Lenna_(test_image).png").unwrap().read_to_end(&mut png).unwrap();
+
+    match &email.sections[1] {
+        Section::Multipart {headers, body, ..} => {
+            assert_eq!(body.len(), 1);
+            assert_eq!(body[0].decoded_body(headers).unwrap(), &png[..300]);
+        },
+        s => panic!("Expected multipart section, got {:?}", s),
+    }
+    assert_eq!(email.sections[2], Section::Empty);
+}
+
+#[test]
+fn walk_headers() {
+    let nested = prepare_nested();
+    let mut message = Message::new(&nested).unwrap();
+
+    let mut count = 0;
+    message.walk_headers(|_| count += 1);
+    assert_eq!(count, 9 + 6);
+
+    message.walk_headers_mut(|header| {
+        if header.key.eq_ignore_ascii_case("content-type") {
+            header.value = String::from("redacted");
+        }
+    });
+
+    let mut content_types = Vec::new();
+    message.walk_headers(|header| {
+        if header.key.eq_ignore_ascii_case("content-type") {
+            content_types.push(header.value.clone());
+        }
+    });
+    assert_eq!(content_types, vec!["redacted"; 7]);
+    assert_eq!(message.headers[0], Header::new("return-path", "<example@gmail.com>"));
+}
+
+#[test]
+fn parse_pgp_encrypted() {
+    let message = prepare_file("test/pgp_encrypted");
+    let email = Message::new(&message).unwrap();
+
+    assert_eq!(email.sections.len(), 3);
+    match email.encrypted_payload() {
+        Some(Section::Multipart {headers, body, ..}) => {
+            assert_eq!(headers[0], Header::new("content-type", r#"application/octet-stream; name="encrypted.asc""#));
+            match &*body[0] {
+                Section::Plain {body, ..} => assert!(body.starts_with(b"-----BEGIN PGP MESSAGE-----")),
+                s => panic!("Expected plain section, got {:?}", s),
+            }
+        },
+        s => panic!("Expected encrypted payload, got {:?}", s),
+    }
+
+    assert_eq!(Message::new(&prepare_multipart()).unwrap().encrypted_payload(), None);
+}
+
+#[test]
+fn parse_forwarded() {
+    let message = prepare_file("test/forwarded");
+    let email = Message::new(&message).unwrap();
+
+    assert_eq!(email.sections.len(), 3);
+    assert_eq!(email.sections[0].content_type(), Some(r#"text/plain; charset="us-ascii""#));
+
+    let forwarded = &email.sections[1];
+    assert_eq!(forwarded.content_type(), Some("message/rfc822"));
+    match forwarded {
+        Section::Multipart {headers, body, ..} => {
+            assert_eq!(headers.len(), 2);
+            assert_eq!(body.len(), 1);
+
+            let embedded = &body[0];
+            assert_eq!(embedded.content_type(), Some(r#"multipart/alternative; boundary="inner_boundary""#));
+            match &**embedded {
+                Section::Multipart {headers, body, ..} => {
+                    assert_eq!(headers.get("subject"), Some("Hello"));
+                    assert_eq!(body.len(), 2);
+                    assert_eq!(body[0].content_type(), Some(r#"text/plain; charset="iso-8859-1""#));
+                    assert_eq!(body[1].content_type(), Some(r#"text/html; charset="iso-8859-1""#));
+                },
+                s => panic!("Expected multipart section, got {:?}", s),
+            }
+        },
+        s => panic!("Expected multipart section, got {:?}", s),
+    }
+
+    assert_eq!(Section::new("Hello, world!").unwrap().content_type(), None);
+}
+
+#[cfg(feature = "testutil")]
+#[test]
+fn testutil_load_message() {
+    let message = super::testutil::load_message("test/multipart_minimal");
+    assert_eq!(message.headers.len(), 3);
+    assert_eq!(super::testutil::load_file("test/plain_minimal"), prepare_plain());
+}
+
+#[test]
+fn parse_content_disposition() {
+    use chrono::DateTime;
+
+    let message = prepare_file("test/disposition");
+    let email = Message::new(&message).unwrap();
+
+    let value = match &email.sections[1] {
+        Section::Multipart {headers, ..} => headers.get("content-disposition").unwrap(),
+        s => panic!("Expected multipart section, got {:?}", s),
+    };
+    let disposition = ContentDisposition::parse(value).unwrap();
+
+    assert_eq!(disposition.disposition, "attachment");
+    assert_eq!(disposition.param("filename"), Some("report.txt"));
+    assert_eq!(disposition.size(), Some(2048));
+    assert_eq!(disposition.creation_date(), Some(DateTime::parse_from_rfc3339("1997-02-12T16:29:51-05:00").unwrap()));
+    assert_eq!(disposition.modification_date(), Some(DateTime::parse_from_rfc3339("1997-02-13T09:15:00-05:00").unwrap()));
+    assert_eq!(disposition.read_date(), None);
+
+    let disposition = ContentDisposition::parse("inline").unwrap();
+    assert_eq!(disposition.disposition, "inline");
+    assert_eq!(disposition.size(), None);
+    assert_eq!(disposition.creation_date(), None);
+
+    assert!(ContentDisposition::parse("").is_err());
+}
+
+#[test]
+fn parse_content_type() {
+    let content_type = ContentType::parse("multipart/alternative;\n boundary=\"000000000000b0bf5b0592287d6c\"").unwrap();
+    assert_eq!(content_type.mime_type, "multipart");
+    assert_eq!(content_type.subtype, "alternative");
+    assert_eq!(content_type.params, vec![(String::from("boundary"), String::from("000000000000b0bf5b0592287d6c"))]);
+    assert!(content_type.is("Multipart", "ALTERNATIVE"));
+
+    let content_type = ContentType::parse("Text/HTML; Charset=\"UTF-8\"; name=\"a; b.html\"").unwrap();
+    assert!(content_type.is("text", "html"));
+    assert_eq!(content_type.param("charset"), Some("UTF-8"));
+    assert_eq!(content_type.param("NAME"), Some("a; b.html"));
+    assert_eq!(content_type.param("boundary"), None);
+    let content_type = ContentType::parse("text/plain; name=\"say \\\"hi\\\".txt\"; x='single'").unwrap();
+    assert_eq!(content_type.param("name"), Some("say \"hi\".txt"));
+    assert_eq!(content_type.param("x"), Some("single"));
+
+    assert!(ContentType::parse("").is_err());
+    assert!(ContentType::parse("text").is_err());
+    assert!(ContentType::parse("text/").is_err());
+    assert!(ContentType::parse("charset=utf-8").is_err());
+
+    let message = Message::new(&prepare_gmail_alt()).unwrap();
+    let content_type = message.content_type().unwrap();
+    assert!(content_type.is("multipart", "mixed"));
+    assert!(content_type.param("boundary").is_some());
+
+    let message = Message::new(&prepare_plain()).unwrap();
+    assert!(message.content_type().unwrap().is("text", "plain"));
+}
+
+#[test]
+fn section_content_disposition() {
+    // Folded, with a single-quoted filename
+    let message = Message::new(&prepare_multipart()).unwrap();
+    let disposition = message.walk().find_map(Section::content_disposition).unwrap();
+    assert_eq!(disposition.disposition, "attachment");
+    assert_eq!(disposition.filename(), Some(String::from("test.txt")));
+
+    let message = Message::new(&prepare_gmail()).unwrap();
+    let dispositions: Vec<ContentDisposition> = message.walk().filter_map(Section::content_disposition).collect();
+    assert_eq!(dispositions.len(), 1);
+    assert_eq!(dispositions[0].param("filename"), Some("Lenna_(test_image).png"));
+
+    let disposition = ContentDisposition::parse("inline;\r\n\tfilename=\"a b.txt\";\n size=10").unwrap();
+    assert_eq!(disposition.disposition, "inline");
+    assert_eq!(disposition.filename(), Some(String::from("a b.txt")));
+    assert_eq!(disposition.size(), Some(10));
+
+    assert_eq!(Section::Empty.content_disposition(), None);
+}
+
+#[test]
+fn parameter_continuations() {
+    let disposition = ContentDisposition::parse("attachment; filename*0=\"quarterly \"; filename*1=\"report.pdf\"").unwrap();
+    assert_eq!(disposition.filename(), Some(String::from("quarterly report.pdf")));
+
+    // Order of the parameters doesn't matter, only their numbering
+    let disposition = ContentDisposition::parse("attachment; filename*1*=%E2%82%AC.txt; filename*0*=UTF-8'en'price%20").unwrap();
+    assert_eq!(disposition.filename(), Some(String::from("price €.txt")));
+
+    let disposition = ContentDisposition::parse("attachment; filename*0*=iso-8859-1''K%F6ln; filename*1=\".txt\"; filename*3=ignored").unwrap();
+    assert_eq!(disposition.filename(), Some(String::from("Köln.txt")));
+
+    let disposition = ContentDisposition::parse("attachment; filename*=UTF-8''%E2%82%AC.txt; filename*0=wrong; filename=fallback.txt").unwrap();
+    assert_eq!(disposition.filename(), Some(String::from("€.txt")));
+
+    // Continuations which don't start at 0 or can't be decoded are ignored
+    let disposition = ContentDisposition::parse("attachment; filename*1=part.txt; filename=fallback.txt").unwrap();
+    assert_eq!(disposition.filename(), Some(String::from("fallback.txt")));
+    let disposition = ContentDisposition::parse("attachment; filename*0*=UTF-8''%ZZ; filename=fallback.txt").unwrap();
+    assert_eq!(disposition.filename(), Some(String::from("fallback.txt")));
+
+    let content_type = ContentType::parse("application/pdf;\n name*0=\"long \";\n name*1=\"name.pdf\"").unwrap();
+    assert_eq!(content_type.decoded_param("name"), Some(String::from("long name.pdf")));
+    assert_eq!(content_type.decoded_param("charset"), None);
+
+    let message = Message::new("Content-Type: multipart/mixed; boundary=x\n\n--x\nContent-Type: text/plain; name*0=a; name*1=b.txt\nContent-Disposition: attachment\n\nHello\n--x--\n").unwrap();
+    assert_eq!(message.attachment_filenames(), vec![String::from("ab.txt")]);
+}
+
+#[test]
+fn reemit_untouched_sections() {
+    let raw = prepare_gmail_alt();
+    let options = ParseOptions { retain_source: true, ..Default::default() };
+    let mut message = Message::with_options(&raw, &options).unwrap();
+    assert_eq!(message.source(), Some(raw.as_str()));
+
+    let spans: Vec<Span> = message.sections.iter().filter_map(|section| match section {
+        Section::Multipart {span, ..} => Some(*span),
+        _ => None,
+    }).collect();
+    assert_eq!(spans.len(), 2);
+
+    // Forward with an extra header, and one part replaced
+    message.headers.push(Header::new("resent-to", "forward@example.com"));
+    message.sections[1] = Section::new("content-type: text/plain\n\nReplaced").unwrap();
+    let forwarded = message.to_string();
+
+    assert!(forwarded.contains("resent-to: forward@example.com\n"));
+    assert!(forwarded.contains(&raw[spans[0].start..spans[0].end]));
+    assert!(!forwarded.contains(&raw[spans[1].start..spans[1].end]));
+    assert!(forwarded.contains("\ncontent-type: text/plain\n\nReplaced\n"));
+
+    // Without the source, every section is re-serialised, and parses back the same
+    let message = Message::new(&raw).unwrap();
+    assert_eq!(message.source(), None);
+    assert_eq!(Message::new(&message.to_string()).unwrap(), message);
+}
+
+#[test]
+fn from_reader() {
+    use std::fs::File;
+    use std::io::{self, Read};
+
+    let message = Message::from_reader(File::open("test/plain_minimal").unwrap()).unwrap();
+    assert_eq!(message.headers.len(), 10);
+
+    struct FailingReader;
+    impl Read for FailingReader {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::ConnectionReset, "connection reset"))
+        }
+    }
+    let e = Message::from_reader(FailingReader).unwrap_err();
+    assert_eq!(e, Error::Io(io::Error::from(io::ErrorKind::ConnectionReset)));
+    assert_eq!(e.to_string(), "Error reading message: connection reset");
+
+    let e = Message::from_reader(&[0xff, 0xfe][..]).unwrap_err();
+    assert_eq!(e, Error::MissingSeparator);
+
+    // With a limit, reading stops one byte past it
+    let raw = std::fs::read("test/plain_minimal").unwrap();
+    let parser = Parser::new(ParseOptions { max_len: Some(raw.len()), ..Default::default() });
+    assert_eq!(parser.parse_reader(&raw[..]).unwrap(), Message::from_bytes(&raw).unwrap());
+    let mut reader = io::Cursor::new(&raw).chain(io::repeat(b'x'));
+    assert_eq!(parser.parse_reader(&mut reader).unwrap_err(), Error::TooLarge);
+    let parser = Parser::new(ParseOptions { max_len: Some(raw.len() - 1), ..Default::default() });
+    let mut reader = io::Cursor::new(&raw);
+    assert_eq!(parser.parse_reader(&mut reader).unwrap_err(), Error::TooLarge);
+    assert_eq!(reader.position(), raw.len() as u64);
+}
+
+#[test]
+fn error_source() {
+    use std::error::Error as _;
+    use std::io;
+
+    assert!(Error::InvalidString.source().is_none());
+    assert!(Error::ParseError.source().is_none());
+    assert!(Error::Unknown.source().is_none());
+
+    let e = Error::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated"));
+    assert_eq!(e.source().unwrap().to_string(), "truncated");
+    assert!(e.source().unwrap().source().is_none());
+
+    // Walking the chain of a parse failure terminates
+    let e = Message::new("Not a MIME document").unwrap_err();
+    let mut chain = Some(&e as &dyn std::error::Error);
+    let mut depth = 0;
+    while let Some(error) = chain {
+        chain = error.source();
+        depth += 1;
+    }
+    assert_eq!(depth, 1);
+}
+
+#[test]
+fn parse_calendar() {
+    let message = prepare_file("test/calendar");
+    let email = Message::new(&message).unwrap();
+
+    assert!(!email.sections[0].is_calendar());
+    assert_eq!(email.sections[0].calendar_method(), None);
+    assert!(email.sections[1].is_calendar());
+    assert_eq!(email.sections[1].calendar_method(), Some(String::from("REQUEST")));
+
+    let section = Section::new("Content-Type: text/calendar\n\nBEGIN:VCALENDAR\nEND:VCALENDAR").unwrap();
+    assert!(section.is_calendar());
+    assert_eq!(section.calendar_method(), None);
+}
+
+#[test]
+fn reuse_parser() {
+    let parser = Parser::new(ParseOptions { retain_source: true, ..Default::default() });
+    assert!(parser.options().retain_source);
+
+    for raw in &[prepare_plain(), prepare_multipart(), prepare_nested()] {
+        let message = parser.parse(raw).unwrap();
+        assert_eq!(message.source(), Some(raw.as_str()));
+        assert_eq!(message.sections, Message::new(raw).unwrap().sections);
+    }
+    assert!(Parser::default().parse("").is_err());
+}
+
+#[test]
+fn duplicate_content_type() {
+    let message = prepare_file("test/duplicate_content_type");
+    let email = Message::new(&message).unwrap();
+
+    assert_eq!(email.sections.len(), 1);
+    match &email.sections[0] {
+        Section::Plain {body, ..} => assert!(body.starts_with(b"--ignored_boundary is not a delimiter")),
+        s => panic!("Expected plain section, got {:?}", s),
+    }
+    assert_eq!(email.validate(), vec![Warning::DuplicateContentType]);
+
+    let section = Section::new("Content-Type: text/plain\nContent-Type: multipart/mixed; boundary=\"x\"\n\n--x\nhello\n--x--").unwrap();
+    match &section {
+        Section::Multipart {body, ..} => assert_eq!(body.len(), 1),
+        s => panic!("Expected multipart section, got {:?}", s),
+    }
+    assert_eq!(section.content_type(), Some("text/plain"));
+}
+
+#[test]
+fn raw_with_headers() {
+    let raw = prepare_gmail_alt();
+    let message = Message::new(&raw).unwrap();
+
+    let attachment = message.sections[1].raw_with_headers(&raw);
+    assert!(attachment.starts_with("Content-Type: image/png; name=\"Lenna_(test_image).png\"\n"));
+    assert!(attachment.ends_with("X-Attachment-Id: f_k0d8idqy0\n\n<snip>\n\n"));
+    assert!(raw.contains(&format!("--0000000000008a01e4059229eec0\n{}--0000000000008a01e4059229eec0--", attachment)));
+
+    match &message.sections[0] {
+        Section::Multipart {body, ..} => {
+            let html = body[1].raw_with_headers(&raw);
+            assert_eq!(html, "Content-Type: text/html; charset=\"UTF-8\"\n\n<div dir=\"ltr\">Hello, world!<br></div>\n\n");
+            match &*body[1] {
+                Section::Multipart {body, ..} => assert_eq!(body[0].raw_with_headers(&raw), "<div dir=\"ltr\">Hello, world!<br></div>\n\n"),
+                s => panic!("Expected multipart section, got {:?}", s),
+            }
+        },
+        s => panic!("Expected multipart section, got {:?}", s),
+    }
+
+    assert_eq!(Section::Empty.raw_with_headers(&raw), "");
+    assert_eq!(message.sections[1].raw_with_headers(""), "");
+}
+
+#[test]
+fn decode_encoded_words() {
+    let message = Message::new("Subject: =?UTF-8?Q?Price:_=E2=82=AC10_=E2=80=94_today?=\nContent-Type: text/plain\n\nHello").unwrap();
+    assert_eq!(message.headers[0].decoded_value().unwrap(), "Price: €10 — today");
+
+    let header = Header::new("subject", "Gr=?ISO-8859-1?Q?=FC=DF?=e aus =?iso-8859-1?q?K=F6ln?=");
+    assert_eq!(header.decoded_value().unwrap(), "Grüße aus Köln");
+
+    let bandcamp = Message::new(&prepare_bandcamp()).unwrap();
+    let subject = bandcamp.headers.iter().find(|h| h.key.eq_ignore_ascii_case("subject")).unwrap();
+    assert_eq!(subject.decoded_value().unwrap(), "New from Malokarpatan: \"Strid\u{17e}ie dni\" red");
+
+    assert_eq!(Header::new("subject", "Hello, world!").decoded_value().unwrap(), "Hello, world!");
+    assert_eq!(Header::new("subject", "=?UTF-8?Q?=E2=82?=").decoded_value(), Err(Error::InvalidString));
+}
+
+#[test]
+fn iter_sections_mut() {
+    let nested = prepare_nested();
+    let mut message = Message::new(&nested).unwrap();
+    let content_types: Vec<Option<String>> = message.iter_sections_mut().map(|s| s.content_type().map(String::from)).collect();
+    assert_eq!(content_types, vec![
+        Some(String::from(r#"text/plain; charset="UTF-8""#)),
+        Some(String::from(r#"text/plain; charset="UTF-8""#)),
+        None,
+    ]);
+
+    // Decode every base64 part in place
+    let mut message = Message::new(&prepare_file("test/base64_tight")).unwrap();
+    for section in message.iter_sections_mut() {
+        if let Section::Multipart {headers, body, ..} = section {
+            if headers.get("content-transfer-encoding") == Some("base64") {
+                let decoded = body[0].decoded_body(headers).unwrap();
+                headers.retain(|h| !h.key.eq_ignore_ascii_case("content-transfer-encoding"));
+                *body[0] = Section::Plain {body: decoded, span: Span::default()};
+            }
+        }
+    }
+    match &message.sections[1] {
+        Section::Multipart {headers, body, ..} => {
+            assert_eq!(headers.len(), 2);
+            match &*body[0] {
+                Section::Plain {body, ..} => assert!(body.starts_with(b"\x89PNG")),
+                s => panic!("Expected plain section, got {:?}", s),
+            }
+        },
+        s => panic!("Expected multipart section, got {:?}", s),
+    }
+}
+
+#[test]
+fn transfer_encoding_with_trailing_tokens() {
+    let body = Section::Plain {body: b"SGVsbG8=".to_vec(), span: Span::default()};
+    for value in &["Base64 ", "base64 (standard)", "base64;", " BASE64\n"] {
+        let headers = Headers::from(vec![Header::new("Content-Transfer-Encoding", value)]);
+        assert_eq!(body.decoded_body(&headers).unwrap(), b"Hello", "{:?}", value);
+    }
+
+    let message = Message::new("Content-Type: text/plain\nContent-Transfer-Encoding: Base64 \n\nSGVsbG8=").unwrap();
+    let headers = Headers::from(vec![Header::new("Content-Transfer-Encoding", "Base64 ")]);
+    assert_eq!(message.sections[0].decoded_body(&headers).unwrap(), b"Hello");
+    assert_eq!(message.sections[0].decoded_body(&message.headers).unwrap(), b"Hello");
+}
+
+#[test]
+fn header_count() {
+    let nested = prepare_nested();
+    let message = Message::new(&nested).unwrap();
+    assert_eq!(message.header_count(), message.headers.len());
+    assert_eq!(message.sections[0].header_count(), 1);
+    assert_eq!(Section::Empty.header_count(), 0);
+    assert_eq!(Section::Plain {body: b"Hello".to_vec(), span: Span::default()}.header_count(), 0);
+}
+
+#[test]
+fn parse_smime() {
+    let message = Message::new(&prepare_file("test/smime_enveloped")).unwrap();
+    assert_eq!(message.smime_type(), Some(SmimeKind::EnvelopedData));
+    assert_eq!(message.encrypted_payload(), None);
+
+    let signed = "Content-Type: multipart/signed; protocol=\"application/pkcs7-signature\"; micalg=sha-256; boundary=x\n\n--x\nContent-Type: text/plain\n\nSigned\n--x\nContent-Type: application/pkcs7-signature; name=smime.p7s\n\nMIAGCSqGSIb3DQEHAqCAMIACAQEx\n--x--";
+    assert_eq!(Message::new(signed).unwrap().smime_type(), Some(SmimeKind::DetachedSignature));
+
+    let opaque = "Content-Type: application/x-pkcs7-mime; smime-type=Signed-Data\n\nMIAGCSqGSIb3DQEHAqCAMIACAQEx";
+    assert_eq!(Message::new(opaque).unwrap().smime_type(), Some(SmimeKind::SignedData));
+
+    let untyped = "Content-Type: application/pkcs7-mime\n\nMIAGCSqGSIb3DQEHAqCAMIACAQEx";
+    assert_eq!(Message::new(untyped).unwrap().smime_type(), Some(SmimeKind::Unknown));
+
+    let pgp = Message::new(&prepare_file("test/pgp_encrypted")).unwrap();
+    assert_eq!(pgp.smime_type(), None);
+}
+
+#[test]
+fn parse_headers_without_trailing_newline() {
+    // Read the fixture directly, as prepare_file would trim any trailing newline anyway
+    let raw = std::fs::read_to_string("test/no_trailing_newline").unwrap();
+    assert!(!raw.ends_with('\n'));
+    let headers = parse_headers(&raw, &ParseOptions::default()).unwrap();
+    assert_eq!(headers.len(), 4);
+    assert_eq!(headers[3], Header::new("x-mailer", "crumble"));
+
+    let headers = parse_headers(&format!("{}\n", raw), &ParseOptions::default()).unwrap();
+    assert_eq!(headers[3], Header::new("x-mailer", "crumble"));
+
+    let headers = parse_headers("Subject: Hi\nX-Mailer:crumble", &ParseOptions::default()).unwrap();
+    assert_eq!(headers[1], Header::new("x-mailer", "crumble"));
+    let headers = parse_headers("Subject: Hi\nX-Empty:", &ParseOptions::default()).unwrap();
+    assert_eq!(headers[1], Header::new("x-empty", ""));
+}
+
+#[test]
+fn parse_malformed_header_separators() {
+    let headers = parse_headers("Subject :Hi\nX-Mailer:crumble\nTo\t:  user@example.com\nX-Note: a: b\nCc:user1@example.com,\n user2@example.com", &ParseOptions::default()).unwrap();
+    assert_eq!(headers, vec![
+        Header::new("subject", "Hi"),
+        Header::new("x-mailer", "crumble"),
+        Header::new("to", "user@example.com"),
+        Header::new("x-note", "a: b"),
+        Header::new("cc", "user1@example.com,\n user2@example.com"),
+    ]);
+
+    // Multibyte values next to a missing space don't shift the neighbouring headers
+    let headers = parse_headers("Subject:Grüße\nX-Mailer :crumble", &ParseOptions::default()).unwrap();
+    assert_eq!(headers, vec![Header::new("subject", "Grüße"), Header::new("x-mailer", "crumble")]);
+
+    // A part which ends after its headers has an empty body rather than panicking
+    let section = Section::new("Content-Type: text/plain\nContent-Transfer-Encoding: 7bit").unwrap();
+    match section {
+        Section::Multipart {headers, body, ..} => {
+            assert_eq!(headers.len(), 2);
+            assert_eq!(body, vec![Box::new(Section::Empty)]);
+        },
+        s => panic!("Expected multipart section, got {:?}", s),
+    }
+}
+
+#[test]
+fn attachment_filenames() {
+    let message = Message::new(&prepare_file("test/attachments")).unwrap();
+    assert_eq!(message.attachment_filenames(), vec![
+        String::from("report.pdf"),
+        String::from("naïve notes.txt"),
+        String::from("Grüße.txt"),
+    ]);
+
+    let disposition = ContentDisposition::parse(r#"attachment; filename="=?ISO-8859-1?Q?K=F6ln.txt?=""#).unwrap();
+    assert_eq!(disposition.filename(), Some(String::from("Köln.txt")));
+    let disposition = ContentDisposition::parse("attachment; filename*=us-ascii'en'plain.txt; filename=fallback.txt").unwrap();
+    assert_eq!(disposition.filename(), Some(String::from("plain.txt")));
+    let disposition = ContentDisposition::parse("attachment; filename*=broken; filename=fallback.txt").unwrap();
+    assert_eq!(disposition.filename(), Some(String::from("fallback.txt")));
+    assert_eq!(ContentDisposition::parse("inline").unwrap().filename(), None);
+
+    let message = Message::new(&prepare_nested()).unwrap();
+    assert!(message.attachment_filenames().is_empty());
+}
+
+#[test]
+fn parse_boundary_with_whitespace() {
+    let message = Message::new(&prepare_file("test/boundary_whitespace")).unwrap();
+    assert_eq!(message.sections.len(), 3);
+    match &message.sections[0] {
+        Section::Multipart {headers, body, ..} => {
+            assert_eq!(headers, &vec![Header::new("content-type", "text/plain")]);
+            assert_eq!(body[0].to_string(), "this is the body text\n\n");
+        },
+        s => panic!("Expected multipart section, got {:?}", s),
+    }
+    match &message.sections[1] {
+        Section::Multipart {headers, body, ..} => {
+            assert_eq!(headers.len(), 2);
+            assert_eq!(headers[1], Header::new("content-disposition", "attachment;\n filename=\"test.txt\""));
+            assert_eq!(body[0].to_string(), "this is the attachment text\n\n");
+        },
+        s => panic!("Expected multipart section, got {:?}", s),
+    }
+    assert_eq!(message.sections[2], Section::Empty);
+    assert_eq!(message.attachment_filenames(), vec![String::from("test.txt")]);
+
+    // Quoted boundaries are used verbatim, whatever the quote style
+    let message = Message::new("Content-Type: multipart/mixed; boundary='a b'\n\n--a b\nContent-Type: text/plain\n\nHello\n--a b--").unwrap();
+    assert_eq!(message.sections[0].content_type(), Some("text/plain"));
+}
+
+#[test]
+fn reencode_headers_ascii() {
+    let raw = "From: Jörg Müller <jorg@example.com>\nSubject: Grüße aus Köln, and a very long subject line which needs more than one encoded-word — ünïcödé\nTo: example@example.com\nContent-Type: multipart/mixed; boundary=x\n\n--x\nContent-Type: text/plain; name=\"Grüße.txt\"\n\nHello\n--x--";
+    let mut message = Message::new(raw).unwrap();
+    message.reencode_headers_ascii();
+    let original = Message::new(raw).unwrap().headers;
+
+    let mut count = 0;
+    message.walk_headers(|header| {
+        assert!(header.value.is_ascii(), "{:?}", header);
+        count += 1;
+    });
+    assert_eq!(count, 5);
+
+    // Non-ASCII words are replaced, ASCII text is left alone
+    assert!(message.headers[0].value.starts_with("=?UTF-8?B?"));
+    assert!(message.headers[0].value.ends_with("?= <jorg@example.com>"));
+    assert!(message.headers[1].value.contains(" and a very long subject line "));
+    assert!(message.headers[1].value.split_whitespace().all(|word| word.len() <= 75));
+    assert_eq!(message.headers[2], original[2]);
+
+    for (header, original) in message.headers.iter().zip(original.iter()) {
+        assert_eq!(header.decoded_value().unwrap(), original.value);
+    }
+    assert_eq!(message.sections[0].content_type(), Some("text/plain; name=\"=?UTF-8?B?R3LDvMOfZS50eHQ=?=\""));
+}
+
+#[test]
+fn embedded_message_charset() {
+    let message = Message::new(&prepare_file("test/forwarded_charset")).unwrap();
+
+    let outer = &message.sections[0];
+    assert_eq!(outer.charset(), Some(String::from("utf-8")));
+    assert_eq!(outer.decoded_text().unwrap(), "Forwarding the message below: Grüße!\n");
+
+    let forwarded = &message.sections[1];
+    assert_eq!(forwarded.charset(), None);
+    assert_eq!(forwarded.decoded_text(), Err(Error::ParseError));
+    let embedded = match forwarded {
+        Section::Multipart {body, ..} => &body[0],
+        s => panic!("Expected multipart section, got {:?}", s),
+    };
+    assert_eq!(embedded.charset(), Some(String::from("iso-8859-1")));
+    assert_eq!(embedded.decoded_text().unwrap(), "Grüße aus Köln\n");
+
+    // The inner parts of an embedded multipart message keep their own charset too
+    let message = Message::new(&prepare_file("test/forwarded")).unwrap();
+    let mut charsets = Vec::new();
+    for section in &message.sections {
+        section.walk(&mut |section| charsets.push(section.charset()));
+    }
+    assert_eq!(charsets, vec![
+        Some(String::from("us-ascii")), None,
+        None, None,
+        Some(String::from("iso-8859-1")), None,
+        Some(String::from("iso-8859-1")), None,
+        None,
+    ]);
+
+    assert_eq!(Section::new("Hello").unwrap().decoded_text().unwrap(), "Hello");
+    assert_eq!(Section::Empty.decoded_text().unwrap(), "");
+}
+
+#[test]
+fn section_header_value() {
+    let message = Message::new(&prepare_file("test/attachments")).unwrap();
+    let sections = &message.sections;
+    assert_eq!(sections[2].header_value("Content-Disposition"), Some(String::from("attachment; filename*=UTF-8''na%C3%AFve%20notes.txt")));
+    assert_eq!(sections[3].header_value("CONTENT-TYPE"), Some(String::from("text/plain; name=\"Grüße.txt\"")));
+    assert_eq!(sections[3].header_value("content-id"), None);
+    assert_eq!(Section::Empty.header_value("content-type"), None);
+
+    let section = Section::new("Subject: =?UTF-8?Q?Gr=C3=BC=C3=9Fe?=\r\n\t=?UTF-8?Q?_aus_K=C3=B6ln?=\r\nContent-Type: text/plain\r\n\r\nHello").unwrap();
+    assert_eq!(section.header_value("subject"), Some(String::from("Grüße aus Köln")));
+}
+
+#[test]
+fn parse_boundary_in_header() {
+    let message = Message::new(&prepare_file("test/boundary_in_header")).unwrap();
+    assert_eq!(message.headers[3].value, r#"multipart/mixed; boundary="sep"; x-comment="parts follow --sep""#);
+    assert_eq!(message.sections.len(), 3);
+    match &message.sections[0] {
+        Section::Multipart {headers, body, ..} => {
+            assert_eq!(headers[0].value, "multipart/alternative; boundary=\"inner\"; x-comment=--inner--sep");
+            assert_eq!(body.len(), 1);
+            assert_eq!(body[0].header_value("content-type"), Some(String::from("text/plain")));
+            assert_eq!(body[0].decoded_text().unwrap(), "First --inner text\n");
+        },
+        s => panic!("Expected multipart section, got {:?}", s),
+    }
+    assert_eq!(message.sections[1].decoded_text().unwrap(), "Second\n");
+    assert_eq!(message.sections[2], Section::Empty);
+}
+
+#[test]
+fn header_entries() {
+    let message = Message::new(&prepare_file("test/multipart_minimal")).unwrap();
+    let entries: Vec<(&str, &str)> = message.header_entries().collect();
+    assert_eq!(entries.len(), message.headers.len());
+    for ((key, value), header) in entries.iter().zip(&message.headers) {
+        assert_eq!(*key, header.key);
+        assert_eq!(*value, header.value);
+    }
+}
+
+#[test]
+fn parse_stray_closing_delimiter() {
+    // A part which only looks like the closing delimiter of a nested part is kept as it is
+    let nested = prepare_nested().replace("--boundary_B--", "--boundary_B\n--boundary_C2--\n\n--boundary_B--");
+    let message = Message::new(&nested).unwrap();
+    match &message.sections[0] {
+        Section::Multipart {body, ..} => {
+            assert_eq!(body.len(), 3);
+            assert!(body[0].content_type().unwrap().contains("boundary_C1"));
+            assert!(body[1].content_type().unwrap().contains("boundary_C2"));
+            assert_eq!(*body[2], Section::new("--boundary_C2--\n\n").unwrap());
+            assert!(body[2].is_plain());
+        },
+        s => panic!("Expected multipart section, got {:?}", s),
+    }
+
+    for raw in &["--child--\n", "----", "--end--", "-- \nSignature"] {
+        assert_eq!(Section::new(raw).unwrap(), Section::Plain {body: raw.as_bytes().to_vec(), span: Span::default()});
+    }
+    assert_eq!(Section::new("--\r\n").unwrap(), Section::Empty);
+    assert_eq!(Section::new("--").unwrap(), Section::Empty);
+}
+
+#[test]
+fn from_str_unchecked() {
+    let plain = prepare_plain();
+    let message = Message::from_str_unchecked(&plain);
+    let expected = Message::new(&plain).unwrap();
+    assert_eq!(message.headers, expected.headers);
+    assert_eq!(message.sections, expected.sections);
+}
+
+#[test]
+#[should_panic(expected = "Could not parse message")]
+fn from_str_unchecked_invalid() {
+    Message::from_str_unchecked("Not a message");
+}
+
+#[test]
+fn duplicate_parameters() {
+    let section = Section::new("Content-Type: text/plain; charset=ascii; charset=utf-8\n\nHello").unwrap();
+    assert_eq!(section.charset(), Some(String::from("utf-8")));
+    assert_eq!(section.content_type_params(), vec![
+        (String::from("charset"), String::from("ascii")),
+        (String::from("charset"), String::from("utf-8")),
+    ]);
+    assert!(Section::Empty.content_type_params().is_empty());
+
+    let disposition = ContentDisposition::parse("attachment; filename=first.txt; FILENAME=second.txt").unwrap();
+    assert_eq!(disposition.param("filename"), Some("second.txt"));
+    assert_eq!(disposition.params.len(), 2);
+}
+
+#[test]
+fn body_contains() {
+    let message = Message::new(&prepare_file("test/forwarded_charset")).unwrap();
+    // Both bodies are base64 encoded
+    assert!(!message.body_contains("Grüße".as_bytes(), false));
+    assert!(message.body_contains("Grüße".as_bytes(), true));
+    assert!(message.body_contains(b"Gr\xfc\xdfe aus K\xf6ln", true));
+    assert!(message.body_contains(b"R3L832Ug", false));
+    assert!(!message.body_contains(b"R3L832Ug", true));
+    assert!(!message.sections[0].body_contains(b"Gr\xfc\xdfe", true));
+    assert!(message.sections[1].body_contains(b"Gr\xfc\xdfe", true));
+
+    // A single part message is decoded with the top-level headers
+    let message = Message::new("Content-Type: text/plain\nContent-Transfer-Encoding: base64\n\nSGVsbG8=").unwrap();
+    assert!(message.body_contains(b"Hello", true));
+    assert!(!message.body_contains(b"Hello", false));
+
+    assert!(Section::new("Hello, world!").unwrap().body_contains(b"world", true));
+    assert!(!Section::Empty.body_contains(b"world", false));
+    assert!(Section::Empty.body_contains(b"", false));
+}
+
+#[test]
+fn parse_empty_boundary() {
+    let raw = prepare_file("test/empty_boundary");
+    let message = Message::new(&raw).unwrap();
+    assert_eq!(message.sections.len(), 1);
+    match &message.sections[0] {
+        Section::Plain {body, ..} => assert_eq!(std::str::from_utf8(body).unwrap(), "--\nNot really -- separated -- at all\n--"),
+        s => panic!("Expected plain section, got {:?}", s),
+    }
+    assert!(message.to_string().contains("\n--\nNot really -- separated -- at all\n--"));
+
+    // The same within a part
+    let section = Section::new("Content-Type: multipart/mixed; boundary=\" \"\n\n-- a\n-- b").unwrap();
+    match section {
+        Section::Multipart {headers, body, ..} => {
+            assert_eq!(headers.len(), 1);
+            assert_eq!(body.len(), 1);
+            assert_eq!(*body[0], Section::Plain {body: b"-- a\n-- b".to_vec(), span: Span::default()});
+        },
+        s => panic!("Expected multipart section, got {:?}", s),
+    }
+}
+
+#[test]
+fn text_content() {
+    // HTML without a plain text alternative is stripped of tags; attachments are text too
+    let message = Message::new(&prepare_file("test/attachments")).unwrap();
+    assert_eq!(message.text_content(), "See attached.\n\nnaive notes\n\nGrüße");
+
+    // Embedded messages are decoded with their own charset
+    let message = Message::new(&prepare_file("test/forwarded_charset")).unwrap();
+    assert_eq!(message.text_content(), "Forwarding the message below: Grüße!\n\nGrüße aus Köln");
+
+    // Only the plain version of an alternative is included
+    let message = Message::new("Content-Type: multipart/alternative; boundary=x\n\n--x\nContent-Type: text/plain\n\nHello & goodbye\n--x\nContent-Type: text/html\n\n<p>Hello &amp; goodbye</p>\n--x--").unwrap();
+    assert_eq!(message.text_content(), "Hello & goodbye");
+    let message = Message::new("Content-Type: multipart/alternative; boundary=x\n\n--x\nContent-Type: text/html\n\n<style>p {}</style><p>Hello &amp; goodbye</p>\n--x--").unwrap();
+    assert_eq!(message.text_content(), "Hello & goodbye");
+
+    let message = Message::new(&prepare_plain()).unwrap();
+    assert!(!message.text_content().is_empty());
+}
+
+#[test]
+fn folded_transfer_encoding() {
+    let raw = "Content-Type: multipart/mixed; boundary=x\n\n--x\nContent-Type: text/plain\nContent-Transfer-Encoding:\r\n\tBASE64\n\nSGVsbG8=\n--x--";
+    let message = Message::new(raw).unwrap();
+    assert!(message.body_contains(b"Hello", true));
+    assert_eq!(message.text_content(), "Hello");
+
+    let body = Section::Plain {body: b"SGVsbG8=".to_vec(), span: Span::default()};
+    let headers = Headers::from(vec![Header::new("Content-Transfer-Encoding", " \r\n base64\r\n")]);
+    assert_eq!(body.decoded_body(&headers).unwrap(), b"Hello");
+}
+
+#[test]
+fn eq_structural() {
+    let raw = prepare_file("test/gmail");
+    let message = Message::new(&raw).unwrap();
+
+    // Re-saved with CRLF line endings, trailing whitespace and reordered headers
+    let resaved: Vec<String> = raw.lines().map(|line| if line.is_empty() { String::new() } else { format!("{}  ", line) }).collect();
+    let resaved = resaved.join("\r\n");
+    let mut resaved = Message::new(&resaved).unwrap();
+    assert!(message.eq_structural(&resaved));
+    resaved.headers.reverse();
+    resaved.headers[0].key = resaved.headers[0].key.to_uppercase();
+    assert!(message.eq_structural(&resaved));
+    assert!(resaved.eq_structural(&message));
+
+    // Content changes are not cosmetic
+    resaved.headers.pop();
+    assert!(!message.eq_structural(&resaved));
+    let changed = Message::new(&raw.replacen("Content-Type: text/plain", "Content-Type: text/html", 1)).unwrap();
+    assert!(!message.eq_structural(&changed));
+    let other = Message::new(&prepare_nested()).unwrap();
+    assert!(!message.eq_structural(&other));
+}
+
+#[test]
+fn inline_images() {
+    let message = Message::new(&prepare_file("test/attachments")).unwrap();
+    let images = message.inline_images();
+    assert_eq!(images.len(), 1);
+    let (media_type, image) = &images["logo"];
+    assert_eq!(media_type, "image/png");
+    assert_eq!(image, b"\x89PNG\r\n\x1a\n");
+
+    // The gmail attachment has a content ID, but its body has been snipped and can't be decoded
+    let message = Message::new(&prepare_file("test/gmail")).unwrap();
+    assert!(message.inline_images().is_empty());
+}
+
+#[test]
+fn decode_without_content_type() {
+    let message = Message::new(&prepare_file("test/encoding_without_type")).unwrap();
+    match &message.sections[0] {
+        Section::Multipart {headers, body, ..} => {
+            assert_eq!(headers, &vec![Header::new("content-transfer-encoding", "base64")]);
+            assert_eq!(body[0].decoded_body(headers).unwrap(), b"Hello, world!");
+        },
+        s => panic!("Expected multipart section, got {:?}", s),
+    }
+    assert_eq!(message.sections[0].content_type(), None);
+    assert_eq!(message.sections[0].decoded_text().unwrap(), "Hello, world!");
+    assert_eq!(message.text_content(), "Hello, world!");
+}
+
+#[test]
+fn canonical_mime() {
+    for file in &["test/gmail", "test/forwarded", "test/attachments", "test/disposition", "test/nested",
+                  "test/plain_minimal", "test/plain_minimal_crlf"] {
+        let message = Message::new(&prepare_file(file)).unwrap();
+        let canonical = message.canonical_mime();
+        assert!(canonical.ends_with("\r\n"), "{}", file);
+        assert!(canonical.split("\r\n").all(|line| !line.contains('\n') && !line.contains('\r')), "{}", file);
+        let reparsed = Message::new(&canonical).unwrap();
+        assert!(message.eq_structural(&reparsed), "{}", file);
+        assert_eq!(reparsed.canonical_mime().as_bytes(), canonical.as_bytes(), "{}", file);
+    }
+
+    let mut message = Message::new(&prepare_plain()).unwrap();
+    let subject = "A very long subject line which goes on and on, well past the seventy-eight character limit for a line";
+    message.headers.push(Header::new("subject", subject));
+    let canonical = message.canonical_mime();
+    assert!(canonical.contains("subject: A very long subject line which goes on and on, well past the\r\n seventy-eight character limit for a line\r\n"));
+    let reparsed = Message::new(&canonical).unwrap();
+    assert!(message.eq_structural(&reparsed));
+    assert_eq!(reparsed.canonical_mime().as_bytes(), canonical.as_bytes());
+    assert!(!message.to_string().contains('\r'));
+}
+
+#[test]
+fn mbox_flags() {
+    let message = Message::new(&prepare_file("test/mbox_flags")).unwrap();
+    assert_eq!(message.mbox_flags(), MboxFlags {
+        read: true,
+        old: true,
+        answered: true,
+        deleted: false,
+        flagged: true,
+        keywords: vec![String::from("$Label1"), String::from("work"), String::from("urgent")],
+    });
+
+    let message = Message::new("Status: O\nX-Status: D\n\nHello").unwrap();
+    let flags = message.mbox_flags();
+    assert!(flags.old && flags.deleted && !flags.read);
+
+    let message = Message::new(&prepare_plain()).unwrap();
+    assert_eq!(message.mbox_flags(), MboxFlags::default());
+}
+
+#[test]
+fn decode_quoted_printable() {
+    let message = Message::new(&prepare_bandcamp()).unwrap();
+    let (headers, body) = match &message.sections[0] {
+        Section::Multipart {headers, body, ..} => (headers, &body[0]),
+        s => panic!("Expected multipart section, got {:?}", s),
+    };
+    let decoded = String::from_utf8(body.decoded_body(headers).unwrap()).unwrap();
+    assert!(decoded.starts_with("\r\nGreetings example,\r\n\r\nMalokarpatan just added \"Strid\u{17e}ie dni\" red to Bandcamp, check it out at:\r\n"));
+    assert!(decoded.contains("merch/strid-ie-dni-red?from=fanpub_fb_merch\r\n"));
+    assert!(decoded.contains("&email=example%40example.com&fan_id=xxxxxx"));
+    // The raw body is untouched
+    match &**body {
+        Section::Plain {body, ..} => assert!(body.starts_with(b"=0D\nGreetings example,=0D\n")),
+        s => panic!("Expected plain section, got {:?}", s),
+    }
+
+    let body = Section::Plain {body: b"a=3Db=\r\nc = d=\n=ZZ=".to_vec(), span: Span::default()};
+    for encoding in &["quoted-printable", "Quoted-Printable"] {
+        let headers = Headers::from(vec![Header::new("content-transfer-encoding", encoding)]);
+        assert_eq!(body.decoded_body(&headers).unwrap(), b"a=bc = d=ZZ");
+    }
+    for encoding in &["7bit", "8bit", "binary"] {
+        let headers = Headers::from(vec![Header::new("content-transfer-encoding", encoding)]);
+        assert_eq!(body.decoded_body(&headers).unwrap(), b"a=3Db=\r\nc = d=\n=ZZ=");
+    }
+
+    let body = Section::Plain {body: b"not base64!".to_vec(), span: Span::default()};
+    let headers = Headers::from(vec![Header::new("content-transfer-encoding", "base64")]);
+    assert_eq!(body.decoded_body(&headers), Err(Error::ParseError));
+    // Soft line breaks are joined, and other line endings kept
+    use super::decode::decode_quoted_printable;
+    assert_eq!(decode_quoted_printable(b"check it out a=\nt:\r\nhere=\r\n.").unwrap(), b"check it out at:\r\nhere.");
+    assert_eq!(decode_quoted_printable(b"").unwrap(), b"");
+}
+
+#[test]
+fn decode_adjacent_encoded_words() {
+    // Whitespace between adjacent words is dropped, but not between a word and literal text
+    let header = Header::new("subject", "=?UTF-8?B?R3LDvMOfZQ==?= \r\n =?UTF-8?Q?_aus?= K=?UTF-8?Q?=C3=B6ln?= !");
+    assert_eq!(header.decoded_value().unwrap(), "Grüße aus Köln !");
+
+    // A character split across two words is joined before conversion
+    let header = Header::new("subject", "=?UTF-8?Q?=E2=82?= =?utf-8?Q?=AC10?=");
+    assert_eq!(header.decoded_value().unwrap(), "€10");
+
+    // Words in different charsets are converted separately
+    let header = Header::new("subject", "=?ISO-8859-1?Q?K=F6ln?= =?UTF-8?Q?_=E2=82=AC?=");
+    assert_eq!(header.decoded_value().unwrap(), "Köln €");
+
+    let header = Header::new("subject", "=?ISO-8859-2?Q?=B9?=");
+    assert_eq!(header.decoded_value(), Err(Error::InvalidString));
+}
+
+#[test]
+fn header_lookup() {
+    let message = Message::new(&prepare_file("test/gmail")).unwrap();
+    assert_eq!(message.header("Subject"), Some("Example"));
+    assert_eq!(message.header("MESSAGE-ID"), message.header("message-id"));
+    assert_eq!(message.header("x-missing"), None);
+
+    let received = message.headers_all("Received");
+    assert_eq!(received.len(), 2);
+    assert!(received[0].starts_with("from mail-ed1-f43.google.com"));
+    assert!(received[1].starts_with("by mail-ed1-f43.google.com"));
+    assert_eq!(message.header("received"), Some(received[0]));
+
+    let message = Message::new(&prepare_bandcamp()).unwrap();
+    assert_eq!(message.headers_all("received").len(), 3);
+    assert!(message.headers_all("x-missing").is_empty());
+}
+
+#[test]
+fn from_bytes() {
+    let raw = std::fs::read("test/latin1_body").unwrap();
+    assert!(std::str::from_utf8(&raw).is_err());
+
+    let message = Message::from_bytes(&raw).unwrap();
+    assert_eq!(message.header("Subject"), Some("Gr\u{fffd}\u{fffd}e"));
+    match &message.sections[0] {
+        Section::Multipart {body, ..} => match &*body[0] {
+            Section::Plain {body, ..} => assert_eq!(body.as_slice(), b"Gr\xfc\xdfe aus K\xf6ln\n\n"),
+            _ => panic!("Expected a plain section"),
+        },
+        _ => panic!("Expected a part with headers"),
+    }
+    assert_eq!(message.sections[0].decoded_text().unwrap(), "Grüße aus Köln\n\n");
+
+    // Valid UTF-8 parses the same either way
+    let raw = prepare_file("test/gmail");
+    let message = Message::from_bytes(raw.as_bytes()).unwrap();
+    assert_eq!(message.to_string(), Message::new(&raw).unwrap().to_string());
+
+    let message = Message::from_reader(&std::fs::read("test/latin1_body").unwrap()[..]).unwrap();
+    assert_eq!(message.headers.len(), 5);
+}
+
+#[test]
+fn parse_multibyte_character_at_scan_limit() {
+    // The header scan only looks at the first 3000 bytes, which here ends inside "é"
+    let headers = "Content-Type: text/plain; charset=\"UTF-8\"\nX-Padding: ";
+    let padding = "a".repeat(2999 - headers.len());
+    let raw = format!("{}{}é\n\nBody\n", headers, padding);
+    assert!(!raw.is_char_boundary(3000));
+
+    let section = Section::new(&raw).unwrap();
+    assert_eq!(section.charset(), Some(String::from("utf-8")));
+    assert_eq!(section.decoded_text().unwrap(), "Body\n");
+
+    let raw = format!("Subject: Padded\nMIME-Version: 1.0\nContent-Type: multipart/mixed; boundary=\"b\"\n\n--b\n{}{}é\n\nBody\n--b--\n", headers, padding);
+    let message = Message::new(&raw).unwrap();
+    assert_eq!(message.sections[0].charset(), Some(String::from("utf-8")));
+}
+
+#[test]
+fn walk() {
+    // Four levels deep: boundary_A contains B, which contains C1 (containing D1) and C2
+    let message = Message::new(&prepare_file("test/nested")).unwrap();
+    let media_types: Vec<Option<&str>> = message.walk()
+        .map(|section| section.content_type().map(|value| value.split(';').next().unwrap()))
+        .collect();
+    assert_eq!(media_types, vec![
+        Some("multipart/alternative"),
+        Some("multipart/alternative"),
+        Some("multipart/alternative"),
+        Some("text/plain"),
+        None,
+        Some("multipart/alternative"),
+        Some("text/plain"),
+        None,
+        None,
+    ]);
+
+    let bodies: Vec<&Section> = message.walk().filter(|section| matches!(section, Section::Plain {..})).collect();
+    assert_eq!(bodies, vec![&Section::Plain {body: b"Level D1\n\n".to_vec(), span: Span::default()}, &Section::Plain {body: b"Level C2\n\n".to_vec(), span: Span::default()}]);
+
+    // Every section visited by iter_sections_mut is also visited here
+    let mut message = Message::new(&prepare_gmail()).unwrap();
+    let walked = message.walk().count();
+    let leaves = message.iter_sections_mut().count();
+    assert!(walked > leaves);
+}
+
+#[test]
+fn compare_and_clone_messages() {
+    let raw = prepare_gmail();
+    let message = Message::new(&raw).unwrap();
+    assert_eq!(message, Message::new(&raw).unwrap());
+
+    let options = ParseOptions { retain_source: true, ..Default::default() };
+    let retained = Message::with_options(&raw, &options).unwrap();
+    assert_eq!(message, retained);
+
+    let mut copy = retained.clone();
+    assert_eq!(copy, message);
+    assert_eq!(copy.source(), Some(raw.as_str()));
+    assert_eq!(copy.to_string(), retained.to_string());
+
+    copy.headers[0].value = String::from("changed");
+    assert_ne!(copy, message);
+
+    let mut copy = message.clone();
+    copy.sections[0] = Section::Empty;
+    assert_ne!(copy, message);
+    assert_ne!(Message::new(&prepare_plain()).unwrap(), message);
+}
+
+#[test]
+fn parse_crlf_line_endings() {
+    // Bodies keep the line endings of the document, so compare them normalised
+    fn lf(section: &mut Section) {
+        match section {
+            Section::Plain {body, ..} => *body = String::from_utf8(body.clone()).unwrap().replace("\r\n", "\n").into_bytes(),
+            Section::Multipart {body, ..} => body.iter_mut().for_each(|section| lf(section)),
+            Section::Empty => (),
+        }
+    }
+
+    let unix = Message::new(&prepare_plain()).unwrap();
+    let mut dos = Message::new(&prepare_file("test/plain_minimal_crlf")).unwrap();
+    assert_eq!(dos.headers, unix.headers);
+    assert_eq!(dos.header("cc"), Some("user1@example.com,\nuser2@example.com"));
+    dos.sections.iter_mut().for_each(lf);
+    assert_eq!(dos, unix);
+
+    for fixture in ["test/gmail", "test/nested", "test/attachments", "test/disposition", "test/bandcamp"] {
+        let raw = prepare_file(fixture).replace("\r\n", "\n");
+        let unix = Message::new(&raw).unwrap();
+        let mut dos = Message::new(&raw.replace('\n', "\r\n")).unwrap();
+        dos.sections.iter_mut().for_each(lf);
+        assert_eq!(dos, unix, "{}", fixture);
+    }
+}
+
+#[test]
+fn parse_depth_limit() {
+    fn nested(depth: usize) -> String {
+        let mut raw = String::from("Subject: Deep\nContent-Type: multipart/mixed; boundary=\"b0x\"\n\n");
+        for level in 0..depth {
+            raw.push_str(&format!("--b{}x\nContent-Type: multipart/mixed; boundary=\"b{}x\"\n\n", level, level + 1));
+        }
+        raw.push_str(&format!("--b{}x\nContent-Type: text/plain\n\nBottom\n", depth));
+        for level in (0..=depth).rev() {
+            raw.push_str(&format!("--b{}x--\n", level));
+        }
+        raw
+    }
+
+    let message = Message::new(&nested(50)).unwrap();
+    assert!(message.body_contains(b"Bottom", false));
+
+    // Far too deep to recurse into, but rejected before the stack is at risk
+    let e = Message::new(&nested(5000)).unwrap_err();
+    assert_eq!(e, Error::ParseError);
+
+    let options = ParseOptions { max_depth: 10, ..Default::default() };
+    assert!(Message::with_options(&nested(9), &options).is_ok());
+    let e = Message::with_options(&nested(10), &options).unwrap_err();
+    assert_eq!(e, Error::ParseError);
+}
+
+#[test]
+fn to_bytes() {
+    for file in &["test/gmail", "test/gmail_alt", "test/bandcamp", "test/multipart_minimal", "test/plain_minimal", "test/attachments", "test/disposition", "test/forwarded", "test/calendar", "test/nested"] {
+        let message = Message::new(&prepare_file(file)).unwrap();
+        let reparsed = Message::from_bytes(&message.to_bytes()).unwrap();
+        assert_eq!(reparsed, message, "{}", file);
+    }
+
+    // Bodies are written as they are, whatever their charset
+    let message = Message::from_bytes(&std::fs::read("test/latin1_body").unwrap()).unwrap();
+    let bytes = message.to_bytes();
+    assert!(bytes.windows(14).any(|window| window == b"Gr\xfc\xdfe aus K\xf6ln"));
+    assert_eq!(Message::from_bytes(&bytes).unwrap(), message);
+
+    // Untouched sections are copied from a retained source
+    let raw = prepare_gmail();
+    let options = ParseOptions { retain_source: true, ..Default::default() };
+    let message = Message::with_options(&raw, &options).unwrap();
+    let bytes = message.to_bytes();
+    let body = &raw[raw.find("\n\n").unwrap() + 2..];
+    assert!(bytes.trim_ascii_end().ends_with(body.trim_end().as_bytes()));
+
+    // Edited and constructed sections are serialised
+    let mut message = Message::new(&prepare_multipart()).unwrap();
+    message.sections.insert(0, Section::new("Content-Type: text/plain\n\nNo final line break").unwrap());
+    let reparsed = Message::from_bytes(&message.to_bytes()).unwrap();
+    assert_eq!(reparsed.sections.len(), message.sections.len());
+    assert_eq!(reparsed.sections[1..], message.sections[1..]);
+    assert!(reparsed.body_contains(b"No final line break\n", false));
+
+    // Empty parts are kept, at the top level and nested; only the closing delimiter's Empty is implied
+    let raw = "Content-Type: multipart/mixed; boundary=x\n\n--x\nOne\n--x\n\n--x\nContent-Type: multipart/mixed; boundary=y\n\n--y\n--y\nTwo\n--y--\n--x\nThree\n--x--\n";
+    let message = Message::new(raw).unwrap();
+    assert_eq!(message.sections.len(), 5);
+    assert_eq!(message.sections[1], Section::Empty);
+    match &message.sections[2] {
+        Section::Multipart {body, ..} => assert_eq!(*body[0], Section::Empty),
+        s => panic!("Expected multipart section, got {:?}", s),
+    }
+    assert_eq!(Message::from_bytes(&message.to_bytes()).unwrap(), message);
+}
+
+#[test]
+fn to_bytes_line_endings() {
+    // Edited sections of a CRLF document are written with CRLF, like the untouched ones
+    let raw = "Content-Type: multipart/mixed; boundary=x\r\n\r\n--x\r\nContent-Type: text/plain\r\n\r\nKept\r\n--x\r\nContent-Type: text/plain\r\n\r\nEdited\r\n--x--\r\n";
+    let options = ParseOptions { retain_source: true, ..ParseOptions::default() };
+    let mut message = Message::with_options(raw, &options).unwrap();
+    assert_eq!(message.to_bytes(), raw.as_bytes());
+    if let Section::Multipart {headers, ..} = &mut message.sections[1] {
+        headers.push(Header::new("X-Edited", "yes"));
+    }
+    let bytes = message.to_bytes();
+    assert!(bytes.windows(2).enumerate().all(|(i, window)| window[1] != b'\n' || window[0] == b'\r' || i == 0));
+    assert!(String::from_utf8(bytes.clone()).unwrap().contains("\r\nX-Edited: yes\r\n\r\nEdited\r\n--x--\r\n"));
+    assert_eq!(Message::from_bytes(&bytes).unwrap(), message);
+
+    // Without the source, the top-level headers give the line ending
+    let message = Message::new(raw).unwrap();
+    assert!(!String::from_utf8(message.to_bytes()).unwrap().replace("\r\n", "").contains('\n'));
+    assert!(!String::from_utf8(Message::new(&raw.replace("\r\n", "\n")).unwrap().to_bytes()).unwrap().contains('\r'));
+
+    // Untouched sections are reparsed with the options the document was parsed with
+    let headers: String = (0..100).map(|i| format!("X-Filler-{}: {}\n", i, "a".repeat(40))).collect();
+    let raw = format!("Content-Type: multipart/mixed; boundary=x\n\n--x\n{}Content-Type:text/plain\n\nHello\n--x--\n", headers);
+    let options = ParseOptions { retain_source: true, header_scan_limit: 10000, ..ParseOptions::default() };
+    let message = Message::with_options(&raw, &options).unwrap();
+    assert!(message.sections[0].is_multipart());
+    assert_eq!(message.to_bytes(), raw.as_bytes());
+}
+
+#[test]
+fn parse_missing_closing_delimiter() {
+    let message = Message::new(&prepare_file("test/truncated_multipart")).unwrap();
+    assert_eq!(message.sections.len(), 2);
+
+    // The alternative part never closes, but keeps both of its parts
+    match &message.sections[0] {
+        Section::Multipart {body, ..} => {
+            assert_eq!(body.len(), 2);
+            assert_eq!(body[0].decoded_text().unwrap(), "Plain version\n\n");
+            assert_eq!(body[1].decoded_text().unwrap(), "<p>HTML version</p>\n\n");
+        },
+        s => panic!("Expected multipart section, got {:?}", s),
+    }
+    assert_eq!(message.sections[1].decoded_text().unwrap(), "Last part, cut off before the closing delimiter");
+
+    // With the closing delimiter, its tail and any epilogue are still dropped
+    let section = Section::new("Content-Type: multipart/alternative; boundary=x\n\n--x\nContent-Type: text/plain\n\nOne\n--x--\nEpilogue\n").unwrap();
+    match section {
+        Section::Multipart {body, ..} => assert_eq!(body.len(), 1),
+        s => panic!("Expected multipart section, got {:?}", s),
+    }
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serde_roundtrip() {
+    for file in &["test/gmail", "test/nested", "test/plain_minimal"] {
+        let message = Message::new(&prepare_file(file)).unwrap();
+        let json = serde_json::to_string(&message).unwrap();
+        let deserialized: Message = serde_json::from_str(&json).unwrap();
+        assert_eq!(deserialized, message, "{}", file);
+        assert_eq!(deserialized.raw_headers(), message.raw_headers());
+    }
+
+    // Bodies which aren't UTF-8 are serialised as base64
+    let message = Message::from_bytes(&std::fs::read("test/latin1_body").unwrap()).unwrap();
+    let json = serde_json::to_value(&message).unwrap();
+    assert_eq!(json["sections"][0]["Multipart"]["body"][0]["Plain"]["body"], "R3L832UgYXVzIEv2bG4KCg==");
+    let deserialized: Message = serde_json::from_value(json).unwrap();
+    assert_eq!(deserialized, message);
+    assert_eq!(deserialized.sections[0].decoded_text().unwrap(), "Grüße aus Köln\n\n");
+}
+
+#[test]
+fn parse_boundary_prefix_of_longer_boundary() {
+    let raw = "Content-Type: multipart/mixed; boundary=\"part\"\n\n--part\nContent-Type: multipart/alternative; boundary=\"part-inner\"\n\n--part-inner\nContent-Type: text/plain\n\nSee --part in the middle of a line\n--partial lines aren't delimiters either\n--part-inner--\n--part\nContent-Type: text/plain\n\nSecond\n--part--\n";
+    let message = Message::new(raw).unwrap();
+    assert_eq!(message.sections.len(), 3);
+    match &message.sections[0] {
+        Section::Multipart {body, ..} => {
+            assert_eq!(body.len(), 1);
+            assert_eq!(body[0].decoded_text().unwrap(), "See --part in the middle of a line\n--partial lines aren't delimiters either\n");
+        },
+        s => panic!("Expected multipart section, got {:?}", s),
+    }
+    assert_eq!(message.sections[1].decoded_text().unwrap(), "Second\n");
+    assert_eq!(message.sections[2], Section::Empty);
+
+    // Transport padding after a delimiter is allowed
+    let message = Message::new("Content-Type: multipart/mixed; boundary=\"x\"\n\n--x \t\nContent-Type: text/plain\n\nOne\n--x--  \n").unwrap();
+    assert_eq!(message.sections[0].decoded_text().unwrap(), "One\n");
+}
+
+#[test]
+fn borrowed_sections() {
+    let raw = prepare_gmail();
+    let borrowed = MessageRef::new(raw.as_bytes()).unwrap();
+    let owned = Message::new(&raw).unwrap();
+    assert_eq!(borrowed.headers, owned.headers);
+    assert_eq!(Message::from(borrowed.clone()), owned);
+
+    // Bodies point into the document rather than being copied
+    match &borrowed.sections[0] {
+        SectionRef::Multipart {body, ..} => match &body[0] {
+            SectionRef::Multipart {body, ..} => match &body[0] {
+                SectionRef::Plain {body, span} => {
+                    assert_eq!(*body, b"Hello, world!\n\n");
+                    assert_eq!(body.as_ptr(), raw[span.start..].as_ptr());
+                },
+                s => panic!("Expected plain section, got {:?}", s),
+            },
+            s => panic!("Expected multipart section, got {:?}", s),
+        },
+        s => panic!("Expected multipart section, got {:?}", s),
+    }
+
+    let raw = "Content-Type: text/plain\n\nHello";
+    let section = SectionRef::new(raw.as_bytes()).unwrap();
+    assert_eq!(section.clone().into_owned(), Section::new(raw).unwrap());
+    assert_eq!(section, SectionRef::new(raw.as_bytes()).unwrap());
+
+    let options = ParseOptions { max_depth: 0, ..Default::default() };
+    assert_eq!(MessageRef::with_options(prepare_gmail().as_bytes(), &options), Err(Error::ParseError));
+}
+
+#[test]
+fn decode_base64() {
+    use super::decode::decode_base64;
+
+    // The PNG signature of the inline logo, with the body's trailing blank lines ignored
+    let message = Message::new(&prepare_file("test/attachments")).unwrap();
+    let logo = message.walk().find_map(|section| match section {
+        Section::Multipart {headers, body, ..} if headers.get("Content-ID").is_some() => match &*body[0] {
+            Section::Plain {body, ..} => Some(body.clone()),
+            _ => None,
+        },
+        _ => None,
+    }).unwrap();
+    assert_eq!(decode_base64(&logo).unwrap(), b"\x89PNG\r\n\x1a\n");
+
+    // Wrapped at 76 columns
+    let encoded: String = base64::encode(&[0xa5; 300]).as_bytes().chunks(76)
+        .map(|line| format!("{}\r\n", std::str::from_utf8(line).unwrap()))
+        .collect();
+    assert_eq!(decode_base64(encoded.as_bytes()).unwrap(), vec![0xa5; 300]);
+
+    assert_eq!(decode_base64(b"iVBORw0K!Ggo="), Err(Error::ParseError));
+    assert_eq!(decode_base64(b"iVBORw0KG"), Err(Error::ParseError));
+}
+
+#[test]
+fn common_headers() {
+    let message = Message::new(&prepare_bandcamp()).unwrap();
+    assert_eq!(message.subject(), Some(String::from("New from Malokarpatan: \"Strid\u{17e}ie dni\" red")));
+
+    let message = Message::new(&prepare_gmail()).unwrap();
+    assert_eq!(message.subject(), Some(String::from("Example")));
+    assert_eq!(message.from_header(), Some(String::from("Example <example@gmail.com>")));
+    assert_eq!(message.to(), Some(String::from("example@example.com")));
+    assert_eq!(message.date(), Some(String::from("Tue, 10 Sep 2019 12:47:31 +1000")));
+    assert_eq!(message.message_id(), message.header("message-id").map(String::from));
+
+    // Folded values are unfolded
+    let message = Message::new("Subject: A long\n subject\nTo: one@example.com,\n\ttwo@example.com\n\nHello").unwrap();
+    assert_eq!(message.subject(), Some(String::from("A long subject")));
+    assert_eq!(message.to(), Some(String::from("one@example.com, two@example.com")));
+    assert_eq!(message.from_header(), None);
+    assert_eq!(message.date(), None);
+    assert_eq!(message.message_id(), None);
+}
+
+#[test]
+fn header_unfolded() {
+    let message = Message::new(&prepare_gmail()).unwrap();
+    let received = message.headers.iter().find(|header| header.key.eq_ignore_ascii_case("received")).unwrap();
+    assert!(received.value.contains("\n\tby example.com"));
+    assert_eq!(received.unfolded(), "from mail-ed1-f43.google.com (mail-ed1-f43.google.com [209.85.208.43]) by example.com (OpenSMTPD) with ESMTPS id ecf00d9e (TLSv1.2:ECDHE-RSA-CHACHA20-POLY1305:256:FAIL) for <example@example.com>; Tue, 10 Sep 2019 02:47:32 +0000 (UTC)");
+    assert!(received.value.contains('\n'));
+
+    let header = Header::new("Subject", " A\r\n   folded\n\tvalue\r\n");
+    assert_eq!(header.unfolded(), " A folded value\r\n");
+    assert_eq!(Header::new("subject", "Unfolded").unfolded(), "Unfolded");
+
+    // The header accessors unfold in the same way
+    let message = Message::new("Subject: A\r\n   folded\n\tvalue\r\nTo: one@example.com,\n  two@example.com\r\n\r\nBody").unwrap();
+    assert_eq!(message.subject(), Some(message.headers[0].unfolded()));
+    assert_eq!(message.to(), Some(message.headers[1].unfolded()));
+}
+
+#[test]
+fn header_map() {
+    let message = Message::new(&prepare_bandcamp()).unwrap();
+    let map = message.header_map();
+    let received: Vec<&str> = message.headers_all("received");
+    assert_eq!(map["received"], received);
+    assert_eq!(map["received"].len(), 3);
+    assert!(map.contains_key("subject"));
+    assert!(!map.contains_key("Subject"));
+    assert_eq!(map.values().map(Vec::len).sum::<usize>(), message.header_count());
+
+    let options = ParseOptions { preserve_header_bytes: true, ..Default::default() };
+    let message = Message::with_options("Subject: one\nSUBJECT: two\n\nHello", &options).unwrap();
+    assert_eq!(message.header_map()["subject"], vec![String::from("one"), String::from("two")]);
+}
+
+#[test]
+fn header_scan_limit() {
+    let raw = format!("Content-Type: multipart/mixed; boundary=x\n\n--x\nX-Padding: {}\nContent-Type: text/plain\n\nHello\n--x--\n", "a".repeat(4000));
+
+    // By default the Content-Type is too far into the part to be found
+    let message = Message::new(&raw).unwrap();
+    assert!(matches!(message.sections[0], Section::Plain {..}));
+
+    let options = ParseOptions { header_scan_limit: 5000, ..Default::default() };
+    let message = Message::with_options(&raw, &options).unwrap();
+    assert_eq!(message.sections[0].content_type(), Some("text/plain"));
+    assert_eq!(message.sections[0].decoded_text().unwrap(), "Hello\n");
+
+    assert_eq!(ParseOptions::default().header_scan_limit, 3000);
+}
+
+#[test]
+fn prune_empty() {
+    let mut message = Message::new(&prepare_file("test/nested")).unwrap();
+    let bytes = message.to_bytes();
+    assert!(message.walk().any(|section| *section == Section::Empty));
+
+    message.prune_empty();
+    assert!(message.walk().all(|section| *section != Section::Empty));
+    let plain = message.walk().filter(|section| matches!(section, Section::Plain {..})).count();
+    assert_eq!(plain, 2);
+    assert_eq!(message.to_bytes(), bytes);
+    let mut reparsed = Message::from_bytes(&bytes).unwrap();
+    reparsed.prune_empty();
+    assert_eq!(reparsed, message);
+
+    // A part with headers but no body keeps its (now empty) multipart section
+    let mut message = Message::new("Content-Type: multipart/mixed; boundary=x\n\n--x\nContent-Type: text/plain\n--x--\n").unwrap();
+    message.prune_empty();
+    assert_eq!(message.sections.len(), 1);
+    match &message.sections[0] {
+        Section::Multipart {body, ..} => assert!(body.is_empty()),
+        _ => panic!("expected a multipart section"),
+    }
+}
+
+#[test]
+fn parse_unquoted_boundary() {
+    let message = Message::new(&prepare_file("test/unquoted_boundary")).unwrap();
+    let parts: Vec<&Section> = message.sections.iter().filter(|section| **section != Section::Empty).collect();
+    assert_eq!(parts.len(), 2);
+    match parts[0] {
+        Section::Multipart {headers, body, ..} => {
+            assert_eq!(headers.get("Content-Type").unwrap(), "multipart/alternative; boundary = alt/1:part?x");
+            let alternatives: Vec<&Box<Section>> = body.iter().filter(|section| ***section != Section::Empty).collect();
+            assert_eq!(alternatives.len(), 2);
+            assert_eq!(alternatives[1].content_type(), Some("text/html; charset=UTF-8"));
+        },
+        _ => panic!("expected a multipart section"),
+    }
+    assert_eq!(parts[1].decoded_text().unwrap(), "Second part\n\n");
+
+    let message = Message::new("Content-Type: multipart/mixed; xboundary=\"nope\"; boundary=yes\n\n--yes\nContent-Type: text/plain\n\nHello\n--yes--").unwrap();
+    assert_eq!(message.sections[0].decoded_text().unwrap(), "Hello\n");
+
+    // A repeated boundary takes its last value, as other parameters do
+    let raw = "Content-Type: multipart/mixed; boundary=first; boundary=\"second\"\n\n--second\nContent-Type: text/plain\n\nHello\n--second--";
+    let message = Message::new(raw).unwrap();
+    assert_eq!(message.headers.boundary().as_deref(), Some("second"));
+    assert_eq!(message.content_type().unwrap().param("boundary"), Some("second"));
+    assert_eq!(message.sections[0].decoded_text().unwrap(), "Hello\n");
+    assert_eq!(Message::from_bytes(&message.to_bytes()).unwrap(), message);
+}
+
+#[test]
+fn content_type_case_and_spacing() {
+    let raw = "CONTENT-TYPE : multipart/mixed; boundary=x\n\n--x\ncontent-TRANSFER-encoding :base64\n\nSGVsbG8=\n--x\nContent-Type:text/plain\n\nWorld\n--x--";
+    let message = Message::new(raw).unwrap();
+    assert_eq!(message.sections.len(), 3);
+    assert!(matches!(message.sections[0], Section::Multipart {..}));
+    assert_eq!(message.sections[0].decoded_text().unwrap(), "Hello");
+    assert_eq!(message.sections[1].content_type(), Some("text/plain"));
+    assert_eq!(message.sections[1].decoded_text().unwrap(), "World\n");
+}
+
+#[test]
+fn visit_nested() {
+    #[derive(Default)]
+    struct Events(Vec<String>);
+
+    impl Visitor for Events {
+        fn visit_plain(&mut self, body: &[u8], depth: usize) {
+            self.0.push(format!("{} plain {}", depth, String::from_utf8_lossy(body).trim()));
+        }
+
+        fn visit_multipart(&mut self, headers: &Headers, depth: usize) {
+            let content_type = headers.get("Content-Type").unwrap_or_default();
+            self.0.push(format!("{} enter {}", depth, content_type.split(';').next().unwrap()));
+        }
+
+        fn leave_multipart(&mut self, _headers: &Headers, depth: usize) {
+            self.0.push(format!("{} leave", depth));
+        }
+
+        fn visit_empty(&mut self, depth: usize) {
+            self.0.push(format!("{} empty", depth));
+        }
+    }
+
+    // Four levels deep: boundary_A contains B, which contains C1 (containing D1) and C2
+    let message = Message::new(&prepare_file("test/nested")).unwrap();
+    let mut events = Events::default();
+    message.visit(&mut events);
+    assert_eq!(events.0, vec![
+        "0 enter multipart/alternative",
+        "1 enter multipart/alternative",
+        "2 enter multipart/alternative",
+        "3 enter text/plain",
+        "4 plain Level D1",
+        "3 leave",
+        "2 leave",
+        "1 leave",
+        "1 enter multipart/alternative",
+        "2 enter text/plain",
+        "3 plain Level C2",
+        "2 leave",
+        "1 leave",
+        "0 leave",
+        "0 empty",
+    ]);
+
+    // The sections visited are those of walk, in the same order
+    let mut events = Events::default();
+    message.sections[0].visit(&mut events);
+    let entered = events.0.iter().filter(|event| !event.ends_with("leave")).count();
+    assert_eq!(entered, message.walk().count() - 1);
+}
+
+#[test]
+fn header_spans() {
+    let raw: &[u8] = b"Subject: Hi\r\nX-Latin: caf\xe9\r\nTo: a@example.com,\r\n b@example.com\r\nContent-Type: multipart/mixed; boundary=x\r\n\r\n--x\r\nContent-Type :text/plain\r\n\r\nHello\r\n--x--\r\n";
+    let message = Message::from_bytes(raw).unwrap();
+    let slices: Vec<&[u8]> = message.headers.iter().map(|header| &raw[header.span.start..header.span.end]).collect();
+    assert_eq!(slices, vec![
+        &b"Subject: Hi"[..],
+        &b"X-Latin: caf\xe9"[..],
+        &b"To: a@example.com,\r\n b@example.com"[..],
+        &b"Content-Type: multipart/mixed; boundary=x"[..],
+    ]);
+
+    match &message.sections[0] {
+        Section::Multipart {headers, ..} => {
+            assert_eq!(&raw[headers[0].span.start..headers[0].span.end], b"Content-Type :text/plain");
+        },
+        _ => panic!("expected a multipart section"),
+    }
+
+    // Spans are the same whether or not the header bytes are preserved
+    let options = ParseOptions { preserve_header_bytes: true, ..Default::default() };
+    let preserved = MessageRef::with_options(raw, &options).unwrap();
+    let spans: Vec<Span> = preserved.headers.iter().map(|header| header.span).collect();
+    assert_eq!(spans, message.headers.iter().map(|header| header.span).collect::<Vec<Span>>());
+
+    // Spans don't affect equality
+    assert_eq!(message.headers[0], Header::new("subject", "Hi"));
+    assert_eq!(Header::new("subject", "Hi").span, Span::default());
+}
+
+#[test]
+fn parse_truncated_multipart_cases() {
+    let headers = "Content-Type: multipart/mixed; boundary=x\n\n";
+    let part = "--x\nContent-Type: text/plain\n\nHi\n";
+    let sections = |body: &str| Message::new(&format!("{}{}", headers, body)).unwrap().sections;
+    let hi = Section::new("Content-Type: text/plain\n\nHi\n").unwrap();
+
+    // No delimiter at all: the body is read as plain
+    assert_eq!(sections("preamble only\n"), vec![Section::Plain {body: b"preamble only\n".to_vec(), span: Span::default()}]);
+
+    // Missing closing delimiter: the last part runs to the end
+    assert_eq!(sections(part), vec![hi.clone()]);
+    assert_eq!(sections(&format!("{}--x\n", part)), vec![hi.clone(), Section::Empty]);
+    assert_eq!(sections("--x\n"), vec![Section::Empty]);
+
+    // Closing delimiter: a trailing Empty, with the epilogue dropped
+    assert_eq!(sections(&format!("{}--x--\n", part)), vec![hi.clone(), Section::Empty]);
+    assert_eq!(sections(&format!("{}--x--\nepilogue\n--x\nignored\n", part)), vec![hi.clone(), Section::Empty]);
+    assert_eq!(sections("--x--\n"), vec![Section::Empty]);
+    assert_eq!(sections("--x\n--x--\n"), vec![Section::Empty, Section::Empty]);
+
+    // Without a body at all the plain parse reports it
+    assert_eq!(Message::new("Content-Type: multipart/mixed; boundary=x").unwrap_err(), Error::MissingSeparator);
+}
+
+#[test]
+fn max_len() {
+    let raw = prepare_file("test/multipart_minimal");
+    let options = ParseOptions { max_len: Some(raw.len() - 1), ..Default::default() };
+    let error = Message::with_options(&raw, &options).unwrap_err();
+    assert_eq!(error, Error::TooLarge);
+    assert_eq!(error.to_string(), "Error parsing message: Document too large");
+    assert_eq!(MessageRef::with_options(raw.as_bytes(), &options).unwrap_err(), Error::TooLarge);
+
+    let options = ParseOptions { max_len: Some(raw.len()), ..Default::default() };
+    assert_eq!(Message::with_options(&raw, &options).unwrap(), Message::new(&raw).unwrap());
+    assert_eq!(ParseOptions::default().max_len, None);
+}
+
+#[test]
+fn parse_address_list() {
+    use super::address::{Address, parse_address_list};
+    let address = |name: Option<&str>, email: &str| Address { display_name: name.map(String::from), email: String::from(email) };
+
+    // Addresses split across lines without folding whitespace, as in the plain fixture's Cc
+    let message = Message::new(&prepare_file("test/plain_minimal")).unwrap();
+    assert_eq!(parse_address_list(message.headers.get("Cc").unwrap()), vec![
+        address(None, "user1@example.com"),
+        address(None, "user2@example.com"),
+    ]);
+    let message = Message::new(&prepare_file("test/attachments")).unwrap();
+    assert_eq!(parse_address_list(message.headers.get("From").unwrap()), vec![address(Some("John Doe"), "example@example.com")]);
+
+    assert_eq!(parse_address_list("\"Doe, John\" <john@example.com>,\n Jane <jane@example.com>; bob@example.com"), vec![
+        address(Some("Doe, John"), "john@example.com"),
+        address(Some("Jane"), "jane@example.com"),
+        address(None, "bob@example.com"),
+    ]);
+    assert_eq!(parse_address_list("\"Quote \\\"Me\\\"\" <q@example.com>"), vec![address(Some("Quote \"Me\""), "q@example.com")]);
+    assert_eq!(parse_address_list(" 'Single Quoted' <s@example.com>"), vec![address(Some("Single Quoted"), "s@example.com")]);
+    assert_eq!(parse_address_list("=?UTF-8?Q?Gr=C3=BC=C3=9Fe?= <g@example.com>"), vec![address(Some("Grüße"), "g@example.com")]);
+
+    // Comments name an address without a display name, and are dropped otherwise
+    assert_eq!(parse_address_list("user@example.com (User, Example)"), vec![address(Some("User, Example"), "user@example.com")]);
+    assert_eq!(parse_address_list("Name (comment) <n@example.com>"), vec![address(Some("Name"), "n@example.com")]);
+
+    // Groups are flattened
+    assert_eq!(parse_address_list("Friends: a@example.com, B <b@example.com>;, c@example.com"), vec![
+        address(None, "a@example.com"),
+        address(Some("B"), "b@example.com"),
+        address(None, "c@example.com"),
+    ]);
+    assert_eq!(parse_address_list("undisclosed-recipients:;"), vec![]);
+    assert_eq!(parse_address_list(""), vec![]);
+}
+
+#[test]
+fn parse_headers_key_like_continuation_lines() {
+    // Indented lines are folds, even when they look like a key
+    let headers = parse_headers("Received: from a.example.com\n\tid: 1234\n        by: b.example.com\nSubject: Hi", &ParseOptions::default()).unwrap();
+    assert_eq!(headers, vec![
+        Header::new("received", "from a.example.com\n\tid: 1234\n        by: b.example.com"),
+        Header::new("subject", "Hi"),
+    ]);
+
+    // The gmail Received values have colons in continuation lines
+    let message = Message::new(&prepare_file("test/gmail")).unwrap();
+    let received: Vec<&Header> = message.headers.iter().filter(|header| header.key.eq_ignore_ascii_case("received")).collect();
+    assert_eq!(received.len(), 2);
+    assert_eq!(received[0].unfolded(), "from mail-ed1-f43.google.com (mail-ed1-f43.google.com [209.85.208.43]) by example.com (OpenSMTPD) with ESMTPS id ecf00d9e (TLSv1.2:ECDHE-RSA-CHACHA20-POLY1305:256:FAIL) for <example@example.com>; Tue, 10 Sep 2019 02:47:32 +0000 (UTC)");
+    assert_eq!(received[1].unfolded(), "by mail-ed1-f43.google.com with SMTP id y91so15364419ede.9 for <example@example.com>; Mon, 09 Sep 2019 19:47:59 -0700 (PDT)");
+}
+
+#[test]
+fn blank_line_scanner_matches_regex() {
+    use super::{find_blank_line, parse_header_bytes};
+    use regex::bytes::Regex;
+    let blank_line = Regex::new(r"\n{2,}|\r{2,}|(\r\n){2,}|(\n\r){2,}").unwrap();
+    let key = Regex::new(r"(?m-u)^[0-9A-Za-z_\-]+[ \t]*:").unwrap();
+
+    let mut inputs: Vec<Vec<u8>> = ["plain_minimal", "plain_minimal_crlf", "multipart_minimal", "gmail", "nested", "latin1_body", "space_params"].iter()
+        .map(|name| std::fs::read(format!("test/{}", name)).unwrap())
+        .collect();
+    for tricky in ["a\n\rb", "a\n\r\nb", "a\r\n\rb", "a\r\r\nb", "a\n\r\n\rb", "a\r\n\r\n\r\nb", "a\rb\n\nc", "", "\n", "\n\n", "a: b\r\nc :d\n\te: f\n-:\n:g\nh\t :"] {
+        inputs.push(tricky.as_bytes().to_vec());
+    }
+
+    for input in &inputs {
+        let expected = blank_line.find(input).map(|separator| (separator.start(), separator.end()));
+        assert_eq!(find_blank_line(input), expected, "{:?}", String::from_utf8_lossy(input));
+
+        let header_block = &input[..expected.map_or(input.len(), |(start, _)| start)];
+        let expected: Vec<(usize, String)> = key.find_iter(header_block)
+            .map(|key| (key.start(), String::from_utf8_lossy(&header_block[key.start()..key.end() - 1]).trim().to_string()))
+            .collect();
+        let headers = parse_header_bytes(header_block, &ParseOptions::default()).unwrap();
+        let found: Vec<(usize, String)> = headers.into_iter().map(|header| (header.span.start, header.key)).collect();
+        assert_eq!(found, expected, "{:?}", String::from_utf8_lossy(header_block));
+    }
+}
+
+#[test]
+fn multipart_predicates() {
+    let message = Message::new(&prepare_file("test/multipart_minimal")).unwrap();
+    assert!(message.is_multipart());
+    assert!(message.sections[0].is_multipart());
+    assert!(!message.sections[0].is_plain());
+    assert!(message.walk().any(Section::is_plain));
+    assert!(!Section::Empty.is_multipart() && !Section::Empty.is_plain());
+
+    let options = ParseOptions { preserve_header_bytes: true, ..Default::default() };
+    assert!(Message::with_options(&prepare_file("test/multipart_minimal"), &options).unwrap().is_multipart());
+
+    let message = Message::new(&prepare_file("test/plain_minimal")).unwrap();
+    assert!(!message.is_multipart());
+    assert!(message.sections[0].is_plain());
+
+    // Without a boundary the body can't be split
+    assert!(!Message::new("Content-Type: multipart/mixed\n\nHello").unwrap().is_multipart());
+
+    // Only the header is checked, even when the boundary never appears and the body is plain
+    let message = Message::new("Content-Type: multipart/mixed; boundary=x\n\nHello").unwrap();
+    assert!(message.is_multipart());
+    assert_eq!(message.sections, vec![Section::Plain {body: b"Hello".to_vec(), span: Span::default()}]);
+}
+
+#[test]
+fn validate_7bit_and_8bit_bodies() {
+    use super::decode::{decode_7bit, decode_8bit};
+    assert_eq!(decode_7bit(b"Hello\r\n").unwrap(), b"Hello\r\n");
+    assert_eq!(decode_7bit(b"caf\xe9"), Err(Error::ParseError));
+    assert_eq!(decode_8bit(b"caf\xe9").unwrap(), b"caf\xe9");
+    assert_eq!(decode_8bit(b"a\0b"), Err(Error::ParseError));
+    let long_line = "a".repeat(999);
+    assert_eq!(decode_8bit(long_line.as_bytes()), Err(Error::ParseError));
+    assert!(decode_8bit(format!("{}\r\n", &long_line[1..]).as_bytes()).is_ok());
+
+    // Mislabelled bodies still decode, but are reported by validate
+    let raw = "Content-Type: multipart/mixed; boundary=x\n\n--x\nContent-Type: text/plain; charset=utf-8\nContent-Transfer-Encoding: 7bit\n\nGr\u{fc}\u{df}e\n--x\nContent-Type: text/plain; charset=utf-8\nContent-Transfer-Encoding: 8bit\n\nGr\u{fc}\u{df}e\n--x--";
+    let message = Message::new(raw).unwrap();
+    assert_eq!(message.sections[0].decoded_text().unwrap(), "Gr\u{fc}\u{df}e\n");
+    assert_eq!(message.validate(), vec![Warning::InvalidTransferEncoding(String::from("7bit"))]);
+
+    let message = Message::new("Content-Transfer-Encoding: 7BIT\n\nGr\u{fc}\u{df}e").unwrap();
+    assert_eq!(message.validate(), vec![Warning::InvalidTransferEncoding(String::from("7bit"))]);
+    assert!(Message::new(&prepare_file("test/plain_minimal")).unwrap().validate().is_empty());
+}
+
+#[test]
+fn try_from() {
+    use std::convert::{TryFrom, TryInto};
+    let raw = prepare_file("test/multipart_minimal");
+    let message: Message = raw.as_str().try_into().unwrap();
+    assert_eq!(message, Message::new(&raw).unwrap());
+    let message = Message::try_from(raw.as_bytes()).unwrap();
+    assert_eq!(message, Message::new(&raw).unwrap());
+
+    let error: Result<Message, Error> = "no separator".try_into();
+    assert_eq!(error.unwrap_err(), Error::MissingSeparator);
+    assert_eq!(Message::try_from(&b"\n\nbody"[..]).unwrap_err(), Error::EmptyHeaders);
+}
+
+#[test]
+fn parse_headers_only() {
+    // Read the fixture directly, as prepare_file would trim the blank line
+    let raw = std::fs::read_to_string("test/headers_only").unwrap();
+    let message = Message::new(&raw).unwrap();
+    assert_eq!(message.headers.len(), 5);
+    assert_eq!(message.subject().as_deref(), Some("Build succeeded"));
+    assert_eq!(message.sections, vec![Section::Empty]);
+    assert_eq!(message.text_content(), "");
+
+    for raw in ["Subject: Hello\n\n", "Subject: Hello\r\n\r\n", "Subject: Hello\n\n \r\n\t\n"] {
+        assert_eq!(Message::new(raw).unwrap().sections, vec![Section::Empty], "{:?}", raw);
+    }
+}
+
+#[test]
+fn section_parsed_content_type() {
+    let message = Message::new(&prepare_file("test/gmail_alt")).unwrap();
+    let types: Vec<(String, String)> = message.walk()
+        .filter_map(Section::parsed_content_type)
+        .map(|content_type| (content_type.mime_type, content_type.subtype))
+        .collect();
+    assert!(types.contains(&(String::from("text"), String::from("plain"))));
+    assert!(types.contains(&(String::from("text"), String::from("html"))));
+
+    let section = Section::new("Content-Type: Text/Plain; charset=\"UTF-8\"\n\nHello").unwrap();
+    let content_type = section.parsed_content_type().unwrap();
+    assert!(content_type.is("text", "plain"));
+    assert_eq!(content_type.param("charset"), Some("UTF-8"));
+
+    assert_eq!(Section::Empty.parsed_content_type(), None);
+    assert_eq!(Section::Plain {body: b"Hello".to_vec(), span: Span::default()}.parsed_content_type(), None);
+    assert_eq!(Section::new("Content-Transfer-Encoding: base64\n\nSGk=").unwrap().parsed_content_type(), None);
+}
+
+#[test]
+fn preamble_kept_apart_from_headers() {
+    let message = Message::new(&prepare_file("test/bandcamp")).unwrap();
+    assert_eq!(message.preamble(), "This is a multi-part message in MIME format.\n");
+    assert!(!message.headers.last().unwrap().value.contains("multi-part"));
+
+    let raw = "Subject: Hi\nContent-Type: multipart/mixed; boundary=x\n\nNote: this is not a header\n--x\nContent-Type: text/plain\n\nHello\n--x--\n";
+    let message = Message::new(raw).unwrap();
+    assert_eq!(message.headers.len(), 2);
+    assert_eq!(message.header("note"), None);
+    assert_eq!(message.preamble(), "Note: this is not a header\n");
+    assert_eq!(message.raw_headers(), "Subject: Hi\nContent-Type: multipart/mixed; boundary=x");
+
+    // The preamble is written back out
+    assert!(message.to_bytes().starts_with(b"Subject: Hi\nContent-Type: multipart/mixed; boundary=x\n\nNote: this is not a header\n--x\n"));
+    assert_eq!(Message::from_bytes(&message.to_bytes()).unwrap().preamble(), message.preamble());
+
+    // Nested parts ignore their preamble
+    let raw = "Content-Type: multipart/mixed; boundary=x\n\n--x\nContent-Type: multipart/alternative; boundary=y\n\nNote: ignored\n--y\nContent-Type: text/plain\n\nHello\n--y--\n--x--\n";
+    let message = Message::new(raw).unwrap();
+    assert_eq!(message.sections[0].headers().len(), 1);
+
+    assert_eq!(Message::new(&prepare_file("test/plain_minimal")).unwrap().preamble(), "");
+}
+
+#[test]
+fn find_sections() {
+    let message = Message::new(&prepare_file("test/attachments")).unwrap();
+    let images = message.find_sections("image/*");
+    assert!(!images.is_empty());
+    assert!(images.iter().all(|section| section.content_type().unwrap().to_lowercase().starts_with("image/")));
+    assert_eq!(message.find_sections("IMAGE/PNG").len(), message.find_sections("image/png").len());
+
+    let message = Message::new(&prepare_file("test/gmail_alt")).unwrap();
+    let html = message.find_sections("text/html");
+    assert_eq!(html.len(), 1);
+    assert!(html[0].decoded_text().unwrap().contains('<'));
+    assert_eq!(message.find_sections("*/*").len(), message.walk().filter(|section| section.content_type().is_some()).count());
+    assert!(message.find_sections("text/x-*").is_empty());
+    assert!(message.find_sections("text").is_empty());
+
+    let content_type = ContentType::parse("application/vnd.ms-excel").unwrap();
+    assert!(content_type.matches("application/vnd.*"));
+    assert!(content_type.matches("*/vnd.ms-excel"));
+    assert!(!content_type.matches("application/pdf"));
+}
+
+#[test]
+fn decode_windows_1252() {
+    let raw: &[u8] = b"Content-Type: multipart/mixed; boundary=x\n\n--x\nContent-Type: text/plain; charset=windows-1252\n\n\x93Caf\xe9\x94 \x80 5\x85\n--x\nContent-Type: text/plain; charset=\"CP1252\"\nContent-Transfer-Encoding: quoted-printable\n\n=93quoted=94 =81\n--x--\n";
+    let message = Message::from_bytes(raw).unwrap();
+    assert_eq!(message.sections[0].decoded_text().unwrap(), "\u{201c}Caf\u{e9}\u{201d} \u{20ac} 5\u{2026}\n");
+    assert_eq!(message.sections[1].decoded_text().unwrap(), "\u{201c}quoted\u{201d} \u{81}\n");
+
+    // ISO-8859-1 keeps the C1 controls
+    let message = Message::from_bytes(b"Content-Type: text/plain; charset=iso-8859-1\n\n\x93Caf\xe9\x94").unwrap();
+    assert_eq!(message.text_content(), "\u{93}Caf\u{e9}\u{94}");
+}
+
+#[test]
+fn header_keys_keep_case() {
+    let message = Message::new(&prepare_gmail()).unwrap();
+    assert_eq!(message.headers[0].key, "Return-Path");
+    assert!(message.headers.iter().any(|header| header.key == "MIME-Version"));
+    assert!(message.header("mime-version").is_some());
+    assert_eq!(message.header("MIME-VERSION"), message.header("Mime-Version"));
+
+    // Keys are compared without case, values exactly
+    assert_eq!(Header::new("Message-ID", "<1@example.com>"), Header::new("message-id", "<1@example.com>"));
+    assert_ne!(Header::new("Message-ID", "<1@example.com>"), Header::new("Message-ID", "<2@example.com>"));
+
+    let headers = parse_headers("Message-ID: <1@example.com>", &ParseOptions { preserve_header_bytes: true, ..ParseOptions::default() }).unwrap();
+    assert_eq!(headers[0].key, "Message-ID");
+}
+
+#[test]
+fn headers_collection() {
+    let message = Message::new(&prepare_gmail()).unwrap();
+    let headers = &message.headers;
+    assert_eq!(headers.get("return-path"), Some("<example@gmail.com>"));
+    assert_eq!(&headers["RETURN-PATH"], "<example@gmail.com>");
+    assert_eq!(headers[0].key, "Return-Path");
+    assert_eq!(headers.get_all("received").count(), 2);
+    assert!(headers.contains("Mime-Version"));
+    assert!(!headers.contains("X-Missing"));
+    assert_eq!(headers.iter().count(), headers.len());
+
+    let mut headers: Headers = vec![Header::new("Subject", "Hi")].into_iter().collect();
+    headers.push(Header::new("To", "a@example.com"));
+    assert_eq!(headers.to_string(), "Subject: Hi\nTo: a@example.com");
+    assert_eq!(headers, vec![Header::new("subject", "Hi"), Header::new("to", "a@example.com")]);
+    assert_eq!(Headers::new().get("Subject"), None);
+}
+
+#[test]
+fn boundary_punctuation() {
+    let multipart = |content_type: &str, boundary: &str| format!(
+        "Content-Type: {}\n\n--{b}\nContent-Type: text/plain\n\nOne\n--{b}\nContent-Type: text/plain\n\nTwo\n--{b}--\n",
+        content_type, b = boundary,
+    );
+    let cases = [
+        // Every character RFC 2046 allows besides letters, digits and space
+        (r#"multipart/mixed; boundary="'()+_,-./:=?""#, "'()+_,-./:=?"),
+        ("multipart/mixed; boundary=(.*)+?", "(.*)+?"),
+        ("multipart/mixed; boundary==_Part.1+2?x", "=_Part.1+2?x"),
+        // ' is a boundary character, so only quotes the value when it encloses all of it
+        ("multipart/mixed; boundary=it's+ok", "it's+ok"),
+        ("multipart/mixed; boundary='quoted+'", "quoted+"),
+        (r#"multipart/mixed; boundary="esc\"aped""#, "esc\"aped"),
+    ];
+    for (content_type, boundary) in cases.iter() {
+        let raw = multipart(content_type, boundary);
+        let message = Message::new(&raw).unwrap();
+        assert_eq!(message.sections.len(), 3, "{}", content_type);
+        assert_eq!(message.sections[0].decoded_text().unwrap(), "One\n", "{}", content_type);
+        assert_eq!(message.sections[1].decoded_text().unwrap(), "Two\n", "{}", content_type);
+        assert_eq!(message.headers.boundary().as_deref(), Some(*boundary));
+        assert_eq!(Message::from_bytes(&message.to_bytes()).unwrap(), message, "{}", content_type);
+    }
+
+    // The boundary is matched literally, so a pattern-like boundary doesn't match other lines
+    let raw = "Content-Type: multipart/mixed; boundary=\"a.c+\"\n\n--a.c+\nContent-Type: text/plain\n\nabc\n--abcc\n--a.c+--\n";
+    let message = Message::new(raw).unwrap();
+    assert_eq!(message.sections[0].decoded_text().unwrap(), "abc\n--abcc\n");
+}
+
+#[test]
+fn display_is_mime() {
+    for file in ["test/gmail", "test/bandcamp", "test/nested", "test/plain_minimal"].iter() {
+        let message = Message::new(&prepare_file(file)).unwrap();
+        assert_eq!(message.to_string().into_bytes(), message.to_bytes(), "{}", file);
+        assert_eq!(Message::new(&message.to_string()).unwrap(), message, "{}", file);
+    }
+
+    let section = Section::new("Content-Type: text/plain\n\nHello").unwrap();
+    assert_eq!(section.to_string(), "Content-Type: text/plain\n\nHello");
+    assert_eq!(Section::new(&section.to_string()).unwrap(), section);
+}
+
+#[test]
+fn debug_tree() {
+    let message = Message::new(&prepare_gmail_alt()).unwrap();
+    assert_eq!(message.debug_tree(), "\
+Message multipart/mixed (7 headers)
+  Multipart multipart/alternative (1 header)
+    Multipart text/plain (1 header)
+      Plain (15 bytes)
+    Multipart text/html (1 header)
+      Plain (40 bytes)
+  Multipart image/png (5 headers)
+    Plain (8 bytes)
+  Empty
+");
+
+    let message = Message::new(&prepare_plain()).unwrap();
+    assert!(message.debug_tree().starts_with("Message text/plain (10 headers)\n  Plain ("));
+}
+
+#[test]
+fn leading_blank_lines() {
+    for prefix in ["\n", "\n\n", "\r\n\r\n", " \n\t\n"].iter() {
+        for file in ["test/plain_minimal", "test/gmail"].iter() {
+            let raw = prepare_file(file);
+            let prefixed = format!("{}{}", prefix, raw);
+            let message = Message::new(&prefixed).unwrap();
+            assert_eq!(message, Message::new(&raw).unwrap(), "{:?} {}", prefix, file);
+            assert_eq!(message.raw_headers(), Message::new(&raw).unwrap().raw_headers());
+
+            // Spans are still positions in the whole document
+            let span = message.headers[0].span;
+            assert_eq!(&prefixed[span.start..span.end], &raw[..span.end - prefix.len()]);
+        }
+    }
+
+    // A document which doesn't start with a header still has no headers
+    assert_eq!(Message::new("\n\nHello, world!").unwrap_err(), Error::EmptyHeaders);
+}
+
+#[test]
+fn attachments() {
+    let message = Message::new(&prepare_file("test/gmail_attachment")).unwrap();
+    let attachments = message.attachments().unwrap();
+    assert_eq!(attachments.len(), 1);
+    assert_eq!(attachments[0].0.as_deref(), Some("Lenna_(test_image).png"));
+    assert!(attachments[0].1.starts_with(b"\x89PNG\r\n\x1a\n"));
+    assert_eq!(attachments[0].1, std::fs::read("test/Lenna_(test_image).png").unwrap());
+
+    // Inline parts count when they have a filename
+    let message = Message::new(&prepare_file("test/attachments")).unwrap();
+    let attachments = message.attachments().unwrap();
+    let filenames: Vec<Option<&str>> = attachments.iter().map(|(filename, _)| filename.as_deref()).collect();
+    assert_eq!(filenames, vec![Some("logo.png"), Some("report.pdf"), Some("naïve notes.txt"), Some("Grüße.txt"), None]);
+    assert_eq!(attachments[1].1, b"%PDF-1.4\n");
+    assert_eq!(attachments[4].1, b"unnamed\n\n");
+
+    // A forwarded message is returned as MIME
+    let message = Message::new("Content-Type: multipart/mixed; boundary=x\n\n--x\nContent-Type: message/rfc822\nContent-Disposition: attachment; filename=fwd.eml\n\nSubject: Hi\n\nHello\n--x--\n").unwrap();
+    let attachments = message.attachments().unwrap();
+    assert_eq!(attachments.len(), 1);
+    assert_eq!(Message::from_bytes(&attachments[0].1).unwrap().subject().as_deref(), Some("Hi"));
+
+    // Malformed contents are an error rather than skipped
+    let message = Message::new("Content-Type: multipart/mixed; boundary=x\n\n--x\nContent-Disposition: attachment\nContent-Transfer-Encoding: base64\n\n*not base64*\n--x--\n").unwrap();
+    assert_eq!(message.attachments(), Err(Error::ParseError));
+
+    assert_eq!(Message::new(&prepare_plain()).unwrap().attachments(), Ok(Vec::new()));
+}
+
+#[test]
+fn q_encoding_underscores() {
+    let subject = |value: &str| Header::new("Subject", value).decoded_value().unwrap();
+    assert_eq!(subject("=?UTF-8?Q?Hello_World?="), "Hello World");
+    assert_eq!(subject("=?utf-8?q?Hello_World?="), "Hello World");
+    assert_eq!(subject("=?UTF-8?Q?snake=5Fcase_name?="), "snake_case name");
+    // Underscores outside encoded-words are left alone
+    assert_eq!(subject("snake_case =?UTF-8?Q?and_more?="), "snake_case and more");
+
+    // A quoted-printable body keeps its underscores
+    let headers = Headers::from(vec![Header::new("Content-Transfer-Encoding", "quoted-printable")]);
+    let body = Section::new("Hello_World=20=5F").unwrap();
+    assert_eq!(body.decoded_body(&headers).unwrap(), b"Hello_World _");
+}
+
+#[test]
+fn section_new_standalone() {
+    struct Counter<'a>(&'a mut usize);
+    impl Visitor for Counter<'_> {
+        fn visit_multipart(&mut self, _headers: &Headers, _depth: usize) {
+            *self.0 += 1;
+        }
+    }
+
+    assert_eq!(Section::new(" \n\t\n").unwrap(), Section::Empty);
+    assert_eq!(Section::new("No headers: just text").unwrap(), Section::Plain {body: b"No headers: just text".to_vec(), span: Span::default()});
+
+    let part = Section::new("Content-Type: multipart/alternative; boundary=y\n\n--y\nContent-Type: text/plain\n\nHi\n--y\nContent-Type: text/html\n\n<p>Hi</p>\n--y--\n").unwrap();
+    assert_eq!(part.content_type(), Some("multipart/alternative; boundary=y"));
+    let mut parts = 0;
+    part.visit(&mut Counter(&mut parts));
+    assert_eq!(parts, 3);
+
+    let part = Section::from_bytes(b"Content-Type: text/plain; charset=iso-8859-1\n\ncaf\xe9").unwrap();
+    assert_eq!(part.decoded_text().unwrap(), "caf\u{e9}");
+}
+
+#[test]
+fn delimiter_transport_padding() {
+    let raw = "Content-Type: multipart/mixed; boundary=x\r\n\r\n--x  \t\r\nContent-Type: text/plain\r\nContent-Transfer-Encoding: base64\r\n\r\nSGVsbG8=\r\n--x \r\nWorld\r\n--x--  \r\n";
+    let message = Message::new(raw).unwrap();
+    assert_eq!(message.sections.len(), 3);
+    assert_eq!(message.sections[0].raw_with_headers(raw), "Content-Type: text/plain\r\nContent-Transfer-Encoding: base64\r\n\r\nSGVsbG8=\r\n");
+    assert_eq!(message.sections[0].decoded_text().unwrap(), "Hello");
+    assert_eq!(message.sections[1], Section::Plain {body: b"World\r\n".to_vec(), span: Span::default()});
+    assert_eq!(message.sections[2], Section::Empty);
+    assert_eq!(Message::from_bytes(&message.to_bytes()).unwrap(), message);
+
+    // The same within a part
+    let section = Section::new("Content-Type: multipart/alternative; boundary=y\n\n--y \nHi\n--y\t\nContent-Type: text/html\n\n<p>Hi</p>\n--y--\n").unwrap();
+    match &section {
+        Section::Multipart {body, ..} => {
+            assert_eq!(*body[0], Section::Plain {body: b"Hi\n".to_vec(), span: Span::default()});
+            assert_eq!(body[1].content_type(), Some("text/html"));
+        },
+        s => panic!("Expected multipart section, got {:?}", s),
+    }
+}
+
+#[test]
+fn parts_starting_with_dashes() {
+    // A part starting with -- (here a signature) is not a closing delimiter, wherever it is
+    let parts = "--x\nContent-Type: text/plain\n\nHello\n--x\n-- \nJohn\n--x\nContent-Type: text/plain\n\nThird\n--x--\n";
+    let message = Message::new(&format!("Content-Type: multipart/mixed; boundary=x\n\n{}", parts)).unwrap();
+    assert_eq!(message.sections.len(), 4);
+    assert_eq!(message.sections[1], Section::Plain {body: b"-- \nJohn\n".to_vec(), span: Span::default()});
+    assert_eq!(message.sections[2].decoded_text().unwrap(), "Third\n");
+    assert_eq!(message.sections[3], Section::Empty);
+
+    let section = Section::new(&format!("Content-Type: multipart/mixed; boundary=x\n\n{}", parts)).unwrap();
+    match &section {
+        Section::Multipart {body, ..} => {
+            assert_eq!(body.len(), 3);
+            assert_eq!(*body[1], Section::Plain {body: b"-- \nJohn\n".to_vec(), span: Span::default()});
+            assert_eq!(body[2].decoded_text().unwrap(), "Third\n");
+        },
+        s => panic!("Expected multipart section, got {:?}", s),
+    }
+
+    // Only the closing delimiter ends the parts, and the epilogue after it is dropped
+    let message = Message::new("Content-Type: multipart/mixed; boundary=x\n\n--x\nOne\n--x--\n--x\nEpilogue\n").unwrap();
+    assert_eq!(message.sections, vec![Section::new("One\n").unwrap(), Section::Empty]);
+}
+
+#[test]
+fn parse_deeply_nested() {
+    let message = Message::new(&deeply_nested(50)).unwrap();
+    let mut section = &message.sections[0];
+    let mut depth = 1;
+    while let Section::Multipart {headers, body, ..} = section {
+        if headers.get("Content-Type") == Some("text/plain") {
+            break;
+        }
+        section = &body[0];
+        depth += 1;
+    }
+    assert_eq!(depth, 50);
+    assert_eq!(section.decoded_text().unwrap(), "Hello\n");
+
+    // Each level is parsed the same however deep it is
+    assert_eq!(Message::new(&deeply_nested(2)).unwrap().debug_tree(), "\
+Message multipart/mixed (2 headers)
+  Multipart multipart/mixed (1 header)
+    Multipart text/plain (1 header)
+      Plain (6 bytes)
+  Empty
+");
+}
+
+#[test]
+fn multipart_alternatives() {
+    let message = Message::new(&prepare_gmail()).unwrap();
+    assert_eq!(message.sections[0].multipart_subtype().as_deref(), Some("alternative"));
+    assert_eq!(message.sections[1].multipart_subtype(), None);
+    assert_eq!(Section::new("Hello").unwrap().multipart_subtype(), None);
+    assert_eq!(Section::new("Content-Type: Multipart/Related; boundary=x\n\n--x\nHi\n--x--").unwrap().multipart_subtype().as_deref(), Some("related"));
+
+    // A nested alternative
+    let best = message.best_alternative().unwrap();
+    assert_eq!(best.content_type(), Some("text/html; charset=\"UTF-8\""));
+
+    // HTML with its images is preferred over plain text, whatever the order
+    let raw = "Content-Type: multipart/alternative; boundary=x\n\n--x\nContent-Type: multipart/related; boundary=y\n\n--y\nContent-Type: text/html\n\n<img src=\"cid:a\">\n--y\nContent-Type: image/png\nContent-ID: <a>\n\n\n--y--\n--x\nContent-Type: text/plain\n\nHello\n--x--\n";
+    let message = Message::new(raw).unwrap();
+    assert_eq!(message.best_alternative().unwrap().multipart_subtype().as_deref(), Some("related"));
+
+    // Otherwise the last of equally preferred parts wins
+    let raw = "Content-Type: multipart/alternative; boundary=x\n\n--x\nContent-Type: text/plain\n\nFirst\n--x\nContent-Type: text/plain\n\nSecond\n--x\nContent-Type: application/pdf\n\n%PDF\n--x--\n";
+    let message = Message::new(raw).unwrap();
+    assert_eq!(message.best_alternative().unwrap().decoded_text().unwrap(), "Second\n");
+
+    assert_eq!(Message::new(&prepare_file("test/attachments")).unwrap().best_alternative(), None);
+    assert_eq!(Message::new(&prepare_plain()).unwrap().best_alternative(), None);
+}
+
+#[test]
+fn find_by_content_id() {
+    let message = Message::new(&prepare_gmail_attachment()).unwrap();
+    let image = message.find_by_content_id("cid:f_k0d8idqy0").unwrap();
+    assert_eq!(image, &message.sections[1]);
+    assert_eq!(message.find_by_content_id("<f_k0d8idqy0>"), Some(image));
+    assert_eq!(message.find_by_content_id("f_k0d8idqy0"), Some(image));
+    assert_eq!(message.find_by_content_id("CID:f_k0d8idqy0"), Some(image));
+    // Content IDs are case-sensitive
+    assert_eq!(message.find_by_content_id("cid:F_K0D8IDQY0"), None);
+    assert_eq!(message.find_by_content_id("cid:missing"), None);
+    match image {
+        Section::Multipart {headers, body, ..} => assert!(body[0].decoded_body(headers).unwrap().starts_with(b"\x89PNG")),
+        s => panic!("Expected multipart section, got {:?}", s),
+    }
+
+    // cid: URLs are percent-encoded, and the part may be nested
+    let message = Message::new(&prepare_file("test/attachments")).unwrap();
+    assert_eq!(message.find_by_content_id("cid:logo").unwrap().content_type(), Some("image/png; name=\"logo.png\""));
+    let message = Message::new("Content-Type: multipart/related; boundary=x\n\n--x\nContent-Type: image/gif\nContent-ID: <a b@example.com>\n\n\n--x--\n").unwrap();
+    assert!(message.find_by_content_id("cid:a%20b@example.com").is_some());
+}
+
+#[test]
+fn section_bytes() {
+    let raw = std::fs::read("test/latin1_body").unwrap();
+    let options = ParseOptions { retain_source: true, ..ParseOptions::default() };
+    let message = Message::parse_bytes(&raw, &options).unwrap();
+    for section in message.walk() {
+        let bytes = message.section_bytes(section).unwrap();
+        assert_eq!(bytes, section.raw_bytes(&raw));
+        if let Section::Plain {body, ..} = section {
+            assert_eq!(bytes, body.as_slice());
+        }
+    }
+
+    // Nested parts keep their exact bytes, CRLF line endings and transfer encoding included
+    let raw = "Content-Type: multipart/mixed; boundary=x\r\n\r\n--x\r\nContent-Type: multipart/signed; boundary=y\r\n\r\n--y\r\nContent-Type: text/plain\r\nContent-Transfer-Encoding: quoted-printable\r\n\r\nSigned=20text\r\n--y\r\nContent-Type: application/pgp-signature\r\n\r\nsig\r\n--y--\r\n--x--\r\n";
+    let mut message = Message::with_options(raw, &options).unwrap();
+    let signed = match &message.sections[0] {
+        Section::Multipart {body, ..} => message.section_bytes(&body[0]).unwrap(),
+        s => panic!("Expected multipart section, got {:?}", s),
+    };
+    assert_eq!(signed, b"Content-Type: text/plain\r\nContent-Transfer-Encoding: quoted-printable\r\n\r\nSigned=20text\r\n");
+
+    // Bytes are still those parsed after a change, but only with the source retained
+    let original = message.section_bytes(&message.sections[0]).unwrap().to_vec();
+    message.headers.push(Header::new("X-Changed", "yes"));
+    if let Section::Multipart {headers, ..} = &mut message.sections[0] {
+        headers.push(Header::new("X-Changed", "yes"));
+    }
+    assert_eq!(message.section_bytes(&message.sections[0]).unwrap(), original.as_slice());
+    assert_eq!(Message::new(raw).unwrap().section_bytes(&message.sections[0]), None);
+    assert_eq!(Section::Empty.raw_bytes(raw.as_bytes()), b"");
+}
+
+#[test]
+fn embedded_messages() {
+    // An embedded message without content headers is still parsed as a message
+    let raw = "Content-Type: multipart/mixed; boundary=x\n\n--x\nContent-Type: text/plain\n\nSee below\n--x\nContent-Type: message/rfc822\n\nFrom: a@example.com\nSubject: Hi\n\nHello\n--x--\n";
+    let message = Message::new(raw).unwrap();
+    match &message.sections[1] {
+        Section::Multipart {body, ..} => assert!(body[0].is_multipart()),
+        s => panic!("Expected multipart section, got {:?}", s),
+    }
+    let embedded = message.sections[1].embedded_message().unwrap();
+    assert_eq!(embedded, Message::new("From: a@example.com\nSubject: Hi\n\nHello\n").unwrap());
+    assert_eq!(embedded.raw_headers(), "From: a@example.com\nSubject: Hi");
+    assert_eq!(message.sections[0].embedded_message(), None);
+    assert_eq!(Section::new("Hello").unwrap().embedded_message(), None);
+
+    // A multipart embedded message matches the same message parsed on its own
+    let message = Message::new(&prepare_file("test/forwarded")).unwrap();
+    let embedded = message.sections[1].embedded_message().unwrap();
+    assert_eq!(embedded.subject().as_deref(), Some("Hello"));
+    assert_eq!(embedded, Message::from_bytes(&embedded.to_bytes()).unwrap());
+    assert_eq!(embedded.sections.len(), 3);
+    assert_eq!(embedded.best_alternative().unwrap().content_type(), Some(r#"text/html; charset="iso-8859-1""#));
+
+    // Even without its closing delimiter, an embedded multipart message ends with the Empty of one,
+    // so it matches the message as to_bytes writes it
+    let inner = "Subject: Cut off\nContent-Type: multipart/mixed; boundary=y\n\n--y\nContent-Type: text/plain\n\nOne\n--y\nContent-Type: text/plain\n\nTwo\n";
+    let message = Message::new(&format!("Content-Type: multipart/mixed; boundary=x\n\n--x\nContent-Type: message/rfc822\n\n{}--x--\n", inner)).unwrap();
+    let embedded = message.sections[0].embedded_message().unwrap();
+    assert_eq!(embedded.sections.len(), 3);
+    assert_eq!(embedded.sections[1].decoded_text().unwrap(), "Two\n");
+    assert_eq!(embedded.sections[2], Section::Empty);
+    assert_eq!(embedded, Message::from_bytes(&embedded.to_bytes()).unwrap());
+    assert_eq!(embedded.sections[..2], Message::new(inner).unwrap().sections[..]);
+
+    // The same section gives the same message, whichever message it came from
+    let copy = message.sections[0].clone();
+    assert_eq!(copy.embedded_message(), Some(embedded));
+
+    // Embedded messages of embedded messages are found too
+    let closed = format!("Content-Type: multipart/mixed; boundary=z\n\n--z\nContent-Type: message/rfc822\n\n{}--y--\n--z--\n", inner);
+    let message = Message::new(&format!("Content-Type: multipart/mixed; boundary=x\n\n--x\nContent-Type: message/rfc822\n\n{}--x--\n", closed)).unwrap();
+    let outer = message.sections[0].embedded_message().unwrap();
+    assert_eq!(outer, Message::new(&closed).unwrap());
+    let embedded = outer.sections[0].embedded_message().unwrap();
+    assert_eq!(embedded.sections.len(), 3);
+    assert_eq!(embedded, Message::new(&format!("{}--y--\n", inner)).unwrap());
+
+    // Embedded messages count towards the depth limit
+    let options = ParseOptions { max_depth: 2, ..ParseOptions::default() };
+    assert!(Message::with_options(raw, &options).is_ok());
+    let options = ParseOptions { max_depth: 1, ..ParseOptions::default() };
+    assert_eq!(Message::with_options(raw, &options), Err(Error::ParseError));
+}
+
+#[test]
+fn parse_lenient() {
+    // Documents which parse are unchanged
+    for file in &["test/gmail", "test/plain_minimal", "test/forwarded"] {
+        let raw = prepare_file(file);
+        assert_eq!(Message::new_lenient(&raw), Message::new(&raw).unwrap());
+    }
+
+    // Anything else is kept whole as plain text
+    for raw in &["Subject: no body", "Hello", "\n\nHello\n"] {
+        assert!(Message::new(raw).is_err());
+        let message = Message::new_lenient(raw);
+        assert!(message.headers.is_empty());
+        assert_eq!(message.sections, vec![Section::Plain {body: raw.as_bytes().to_vec(), span: Span {start: 0, end: raw.len()}}]);
+        assert_eq!(message.text_content(), raw.trim());
+    }
+    assert_eq!(Message::new_lenient("").sections, vec![Section::Empty]);
+    assert_eq!(Message::new_lenient("\n\n").sections, vec![Section::Empty]);
+
+    // Documents nested too deeply for the default limit are kept too
+    let raw = deeply_nested(200);
+    assert!(Message::new(&raw).is_err());
+    assert!(Message::new_lenient(&raw).sections[0].is_plain());
+}
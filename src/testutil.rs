@@ -0,0 +1,31 @@
+//! Helpers for loading MIME documents from fixture files in tests.
+//!
+//! Enabled with the `testutil` feature. These helpers panic on failure, so they are only suitable
+//! for tests and known-good input.
+
+use super::Message;
+use std::path::Path;
+
+/// Read a fixture file into a String, trimming surrounding whitespace.
+///
+/// # Panics
+/// Panics if the file cannot be read.
+pub fn load_file<P: AsRef<Path>>(path: P) -> String {
+    let path = path.as_ref();
+    match std::fs::read_to_string(path) {
+        Ok(contents) => contents.trim().to_string(),
+        Err(e) => panic!("Could not read {}: {:?}", path.display(), e),
+    }
+}
+
+/// Read and parse a fixture file.
+///
+/// # Panics
+/// Panics if the file cannot be read or parsed.
+pub fn load_message<P: AsRef<Path>>(path: P) -> Message {
+    let path = path.as_ref();
+    match Message::new(&load_file(path)) {
+        Ok(message) => message,
+        Err(e) => panic!("Could not parse {}: {:?}", path.display(), e),
+    }
+}